@@ -0,0 +1,346 @@
+// src/archive.rs
+use crate::file_manager::{ FileManager, WriteError };
+use crate::import::{ self, ImportReport };
+use crate::quota::normalize_prefix;
+use crate::recipe::{ FileKind, FileMeta, FileRecipe };
+use std::ffi::OsStr;
+use std::io::{ self, Read, Write };
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Component, Path };
+use std::sync::Arc;
+use tar::{ Archive, Builder, EntryType, Header };
+
+// Most buffer reserved up front for an entry: its header can claim any size,
+// and a truncated stream must fail as one rather than on the allocation
+const MAX_PREALLOCATE: usize = 16 * 1024 * 1024;
+
+/// Stores every entry of the tar stream `reader` under `prefix`, with its
+/// mode and mtime. Hard links become a second file sharing the chunks of the
+/// first. Devices, FIFOs and entries escaping the prefix are skipped.
+pub fn import_tar(manager: &FileManager, reader: impl Read, prefix: &str) -> Result<ImportReport, String> {
+    let prefix = normalize_prefix(prefix);
+    import::make_prefix(manager, &prefix)?;
+    let mut report = ImportReport::default();
+    let mut archive = Archive::new(reader);
+
+    for entry in archive.entries().map_err(|e| format!("Bad tar stream: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Bad tar stream: {}", e))?;
+        let name = entry.path().map(|path| path.display().to_string()).unwrap_or_default();
+        let Some(dest) = entry.path().ok().and_then(|path| repository_path(&prefix, &path)) else {
+            report.skipped.push(format!("{}: not a relative UTF-8 path inside the archive", name));
+            continue;
+        };
+        let header = entry.header();
+        let meta = FileMeta {
+            mode: header.mode().unwrap_or(0) & 0o7777,
+            mtime_secs: header.mtime().unwrap_or(0) as i64,
+            mtime_nanos: 0,
+        };
+        let entry_type = header.entry_type();
+
+        // Archives often leave out the directories above an entry. A file
+        // where one should be (in the archive or already stored) would leave
+        // the entry orphaned below it.
+        if let Some((parent, _)) = dest.rsplit_once('/') {
+            if file_in_the_way(manager, parent) {
+                report.skipped.push(format!("{}: {}", name, WriteError::NotDirectory));
+                continue;
+            }
+            import::make_prefix(manager, parent)?;
+        }
+        let stored = match entry_type {
+            EntryType::Directory => manager.ensure_directory(&dest, meta).map(|()| report.directories += 1),
+            EntryType::Regular | EntryType::Continuous => {
+                let mut data = Vec::with_capacity((entry.size() as usize).min(MAX_PREALLOCATE));
+                entry.read_to_end(&mut data).map_err(|e| format!("{}: {}", name, e))?;
+                report.bytes += data.len() as u64;
+                manager
+                    .write_file_with_meta(&dest, &data, FileKind::File, meta)
+                    .map(|()| report.files += 1)
+            }
+            EntryType::Symlink => {
+                let target = entry.link_name_bytes().map(|target| target.into_owned()).unwrap_or_default();
+                manager
+                    .write_file_with_meta(&dest, &target, FileKind::Symlink, meta)
+                    .map(|()| report.symlinks += 1)
+            }
+            EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .ok()
+                    .flatten()
+                    .and_then(|target| repository_path(&prefix, &target));
+                let Some(target) = target else {
+                    report.skipped.push(format!("{}: hard link to a path outside the archive", name));
+                    continue;
+                };
+                manager.clone_file(&target, &dest).map(|()| report.files += 1)
+            }
+            other => {
+                report.skipped.push(format!("{}: unsupported entry type {:?}", name, other));
+                continue;
+            }
+        };
+        if let Err(e) = stored {
+            import::skip_or_fail(e, Path::new(&name), &mut report.skipped)?;
+        }
+    }
+    Ok(report)
+}
+
+// Whether `dir` or a directory above it is stored as a file or symlink
+fn file_in_the_way(manager: &FileManager, dir: &str) -> bool {
+    dir.match_indices('/')
+        .map(|(i, _)| &dir[..i])
+        .chain([dir])
+        .any(|ancestor| matches!(manager.get_file_metadata(ancestor), Some((_, FileKind::File | FileKind::Symlink))))
+}
+
+// "./a/b" -> "<prefix>/a/b"; None for names that climb out ("../x"), aren't
+// UTF-8 or are the root itself. A leading "/" is dropped, as tar does.
+fn repository_path(prefix: &str, path: &Path) -> Option<String> {
+    let mut dest = prefix.to_string();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part.to_str()?;
+                dest = if dest.is_empty() { part.to_string() } else { format!("{}/{}", dest, part) };
+            }
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!dest.is_empty()).then_some(dest)
+}
+
+/// Writes `path` (a file or a directory and everything below it; "" is the
+/// whole repository) as a tar stream, named as `export` would restore it.
+/// File content is read one chunk at a time, so memory use doesn't grow with
+/// file size.
+pub fn export_tar(manager: &FileManager, path: &str, writer: impl Write) -> Result<usize, String> {
+    let path = normalize_prefix(path);
+    let paths = manager.subtree_paths(&path)?;
+    if paths.is_empty() && !path.is_empty() {
+        return Err(format!("'{}' not found", path));
+    }
+    // Names start at the last component of `path`, so `docs` gives docs/...
+    let strip = path.rfind('/').map_or(0, |slash| slash + 1);
+
+    let mut builder = Builder::new(writer);
+    let mut entries = 0;
+    for entry in paths {
+        let name = &entry[strip..];
+        if name.is_empty() {
+            continue; // The repository root has no entry of its own
+        }
+        let recipe = manager.load_recipe(&entry)?;
+        let failed = |e: io::Error| format!("{}: {}", entry, e);
+        let mut header = Header::new_gnu();
+        header.set_mode(mode_of(&recipe));
+        header.set_mtime(mtime_of(&recipe.meta));
+        match recipe.kind {
+            FileKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, name, io::empty()).map_err(failed)?;
+            }
+            FileKind::Symlink => {
+                let target = manager.read_recipe_range(&recipe, 0, recipe.file_size)?;
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, name, OsStr::from_bytes(&target)).map_err(failed)?;
+            }
+            FileKind::File => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(recipe.file_size);
+                let content = RecipeReader::new(manager, recipe);
+                builder.append_data(&mut header, name, content).map_err(failed)?;
+            }
+        }
+        entries += 1;
+    }
+    builder.into_inner().and_then(|mut writer| writer.flush()).map_err(|e| e.to_string())?;
+    Ok(entries)
+}
+
+fn mode_of(recipe: &FileRecipe) -> u32 {
+    match recipe.kind {
+        _ if recipe.meta.mode != 0 => recipe.meta.mode,
+        FileKind::File => 0o644,
+        FileKind::Directory => 0o755,
+        FileKind::Symlink => 0o777,
+    }
+}
+
+// Entries with no recorded mtime are stamped with the time of the export
+fn mtime_of(meta: &FileMeta) -> u64 {
+    match meta.mtime() {
+        Some(_) => meta.mtime_secs.max(0) as u64,
+        None => FileMeta::now().mtime_secs as u64,
+    }
+}
+
+/// A recipe's content as a `Read`: one verified chunk in memory at a time,
+/// and holes produced as zeros without being allocated
+struct RecipeReader<'a> {
+    manager: &'a FileManager,
+    recipe: FileRecipe,
+    // Next chunk to load
+    next: usize,
+    current: Arc<Vec<u8>>,
+    position: usize,
+    hole_left: u64,
+}
+
+impl<'a> RecipeReader<'a> {
+    fn new(manager: &'a FileManager, recipe: FileRecipe) -> Self {
+        RecipeReader { manager, recipe, next: 0, current: Arc::new(Vec::new()), position: 0, hole_left: 0 }
+    }
+}
+
+impl Read for RecipeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.current.len() {
+                let n = buf.len().min(self.current.len() - self.position);
+                buf[..n].copy_from_slice(&self.current[self.position..self.position + n]);
+                self.position += n;
+                return Ok(n);
+            }
+            if self.hole_left > 0 {
+                let n = (buf.len() as u64).min(self.hole_left) as usize;
+                buf[..n].fill(0);
+                self.hole_left -= n as u64;
+                return Ok(n);
+            }
+            let Some(chunk) = self.recipe.chunks.get(self.next) else {
+                return Ok(0);
+            };
+            self.next += 1;
+            if chunk.is_hole() {
+                self.hole_left = chunk.size;
+            } else {
+                self.current = self.manager.read_verified_chunk(&chunk.hash).map_err(io::Error::other)?;
+                self.position = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ noise, reset };
+    use std::fs;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_tar_import_and_export() {
+        let db_path = "./test_db_tar";
+        reset(db_path);
+        let manager = FileManager::new(db_path);
+
+        // 1. A tarball with a file in an unlisted directory, a symlink and a hard link
+        let big = noise(150_000, 0);
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(big.len() as u64);
+        header.set_mode(0o640);
+        header.set_mtime(1_700_000_000);
+        builder.append_data(&mut header, "./src/deep/data.bin", big.as_slice()).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "src/latest", "deep/data.bin").unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "copy.bin", "src/deep/data.bin").unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        let report = import_tar(&manager, Cursor::new(tarball), "restored").unwrap();
+        assert_eq!((report.files, report.symlinks), (2, 1));
+        assert_eq!(manager.read_file("restored/src/deep/data.bin").unwrap(), big);
+        assert_eq!(manager.get_file_metadata("restored/src/deep"), Some((0, FileKind::Directory)));
+        let (_, _, meta) = manager.stat("restored/src/deep/data.bin").unwrap();
+        assert_eq!((meta.mode, meta.mtime_secs), (0o640, 1_700_000_000));
+        assert_eq!(manager.read_file("restored/src/latest").unwrap(), b"deep/data.bin");
+        assert_eq!(
+            manager.load_recipe("restored/copy.bin").unwrap().chunks,
+            manager.load_recipe("restored/src/deep/data.bin").unwrap().chunks
+        );
+
+        // 2. Streaming a subtree back out, with a hole in one file
+        manager.punch_hole("restored/copy.bin", 10_000, 100_000).unwrap();
+        let mut exported = Vec::new();
+        assert_eq!(export_tar(&manager, "/restored/", &mut exported).unwrap(), 6);
+        let mut archive = Archive::new(Cursor::new(exported));
+        let mut seen = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().display().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            match name.as_str() {
+                "restored/src/deep/data.bin" => {
+                    assert_eq!(data, big);
+                    assert_eq!(entry.header().mode().unwrap(), 0o640);
+                }
+                "restored/copy.bin" => {
+                    assert!(data[10_000..110_000].iter().all(|&b| b == 0));
+                    assert_eq!(data[110_000..], big[110_000..]);
+                }
+                "restored/src/latest" => {
+                    assert_eq!(entry.link_name().unwrap().unwrap(), Path::new("deep/data.bin"));
+                }
+                _ => {}
+            }
+            seen.push(name);
+        }
+        assert_eq!(seen[0], "restored");
+        assert!(export_tar(&manager, "missing", io::sink()).is_err());
+
+        // 3. A header claiming far more than the stream holds is an error, not an abort
+        let mut header = Header::new_gnu();
+        header.set_size(1 << 60);
+        header.set_cksum();
+        let mut truncated = header.as_bytes().to_vec();
+        truncated.extend_from_slice(&[1u8; 1024]);
+        assert!(import_tar(&manager, Cursor::new(truncated), "truncated").is_err());
+
+        fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_tar_import_never_puts_members_under_a_file() {
+        let db_path = "./test_db_tar_orphans";
+        reset(db_path);
+        let manager = FileManager::new(db_path);
+        manager.create_directory("restored").unwrap();
+        manager.write_file("restored/stored", b"already a file").unwrap();
+
+        // "notes" is a file in the archive, "stored" one in the repository
+        let mut builder = Builder::new(Vec::new());
+        for (name, content) in [("notes", "a file"), ("notes/inside.txt", "x"), ("stored/deep/y.txt", "y"), ("ok.txt", "z")] {
+            let mut header = Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        let report = import_tar(&manager, Cursor::new(tarball), "restored").unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.skipped, vec![
+            "notes/inside.txt: Not a directory".to_string(),
+            "stored/deep/y.txt: Not a directory".to_string()
+        ]);
+        assert_eq!(manager.read_file("restored/notes").unwrap(), b"a file");
+        assert_eq!(manager.read_file("restored/ok.txt").unwrap(), b"z");
+        for orphan in ["restored/notes/inside.txt", "restored/stored/deep", "restored/stored/deep/y.txt"] {
+            assert!(manager.get_file_metadata(orphan).is_none(), "{}", orphan);
+        }
+
+        fs::remove_dir_all(db_path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+// src/chunk_cache.rs
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+
+/// Snapshot of the cache counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 { 0.0 } else { (self.hits as f64) / (total as f64) }
+    }
+}
+
+/// Least-recently-used cache of decompressed chunks, bounded by total bytes
+/// rather than entry count (chunks range from a few bytes to 64KB).
+/// Safe to share between threads.
+pub struct ChunkCache {
+    inner: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct LruState {
+    capacity: usize,
+    bytes: usize,
+    // Each access stamps the entry with a new tick; the smallest tick is the LRU
+    tick: u64,
+    entries: HashMap<String, (Arc<Vec<u8>>, u64)>,
+    by_age: BTreeMap<u64, String>,
+}
+
+impl ChunkCache {
+    /// A capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        ChunkCache {
+            inner: Mutex::new(LruState {
+                capacity,
+                bytes: 0,
+                tick: 0,
+                entries: HashMap::new(),
+                by_age: BTreeMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.inner.lock().unwrap();
+        let tick = state.next_tick();
+        let LruState { entries, by_age, .. } = &mut *state;
+        match entries.get_mut(id) {
+            Some((data, last_used)) => {
+                by_age.remove(last_used);
+                by_age.insert(tick, id.to_string());
+                *last_used = tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(data.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, id: &str, data: Arc<Vec<u8>>) {
+        let mut state = self.inner.lock().unwrap();
+        // Something bigger than the whole cache would just flush everything else
+        if data.len() > state.capacity {
+            return;
+        }
+        state.remove(id);
+        while state.bytes + data.len() > state.capacity {
+            state.evict_oldest();
+        }
+        let tick = state.next_tick();
+        state.bytes += data.len();
+        state.by_age.insert(tick, id.to_string());
+        state.entries.insert(id.to_string(), (data, tick));
+    }
+
+    /// Drops a chunk (e.g. after GC deleted it)
+    pub fn invalidate(&self, id: &str) {
+        self.inner.lock().unwrap().remove(id);
+    }
+
+    /// Changes the byte budget, evicting as needed
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.inner.lock().unwrap();
+        state.capacity = capacity;
+        while state.bytes > capacity {
+            state.evict_oldest();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.inner.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: state.entries.len(),
+            bytes: state.bytes,
+            capacity: state.capacity,
+        }
+    }
+}
+
+impl LruState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some((data, tick)) = self.entries.remove(id) {
+            self.by_age.remove(&tick);
+            self.bytes -= data.len();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, id)) = self.by_age.pop_first() {
+            let (data, _) = self.entries.remove(&id).unwrap();
+            self.bytes -= data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![7; len])
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_by_size() {
+        let cache = ChunkCache::new(300);
+        cache.insert("a", chunk(100));
+        cache.insert("b", chunk(100));
+        cache.insert("c", chunk(100));
+
+        // Touch "a" so "b" becomes the oldest
+        assert!(cache.get("a").is_some());
+        cache.insert("d", chunk(150));
+
+        assert!(cache.get("b").is_none(), "LRU entry should be gone");
+        assert!(cache.get("c").is_none(), "Needed a second eviction to fit 150 bytes");
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("d").is_some());
+        assert_eq!(cache.stats().bytes, 250);
+    }
+
+    #[test]
+    fn test_counts_hits_and_misses() {
+        let cache = ChunkCache::new(1024);
+        assert!(cache.get("x").is_none());
+        cache.insert("x", chunk(10));
+        assert!(cache.get("x").is_some());
+        assert!(cache.get("x").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert!((stats.hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_oversized_and_disabled() {
+        let cache = ChunkCache::new(50);
+        cache.insert("big", chunk(51));
+        assert!(cache.get("big").is_none());
+
+        cache.insert("small", chunk(50));
+        cache.set_capacity(0);
+        assert!(cache.get("small").is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let cache = Arc::new(ChunkCache::new(64 * 1024));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let id = format!("{}", (i + t) % 50);
+                        if cache.get(&id).is_none() {
+                            cache.insert(&id, chunk(512));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 800);
+        assert!(stats.bytes <= stats.capacity);
+    }
+}
@@ -83,7 +83,7 @@ mod tests {
         }
         
         println!("Found {} chunks at positions: {:?}", cut_points.len(), &cut_points[..cut_points.len().min(10)]);
-        println!("Average chunk size: ~{} bytes", if cut_points.len() > 0 { 100_000 / cut_points.len() } else { 0 });
+        println!("Average chunk size: ~{} bytes", if !cut_points.is_empty() { 100_000 / cut_points.len() } else { 0 });
         
         assert!(cut_points.len() > 5, "Statistically unlikely to have fewer than 5 chunks in 100KB data");
     }
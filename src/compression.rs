@@ -0,0 +1,295 @@
+// src/compression.rs
+use serde::{ Deserialize, Serialize };
+use std::fmt;
+use std::io::{ Error, ErrorKind, Read, Write };
+use std::str::FromStr;
+
+// Every chunk written since adaptive compression starts with a small header:
+//   v1: [b"BFC"][1][codec: u8][payload...]
+//   v2: [b"BFC"][2][codec: u8][dictionary id: u32 LE][payload...]
+// v2 is only written when a trained dictionary was used. Older chunks are
+// bare zstd frames (magic 0x28B52FFD), which can never start with "BFC",
+// so all layouts are told apart by their first bytes.
+const CHUNK_MAGIC: &[u8; 3] = b"BFC";
+const HEADER_LEN: usize = CHUNK_MAGIC.len() + 2;
+const DICT_HEADER_LEN: usize = HEADER_LEN + 4;
+
+/// A trained zstd dictionary, identified by the ID recorded in chunk headers
+pub struct Dictionary {
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Dictionaries are named after their content so retraining on the same
+    /// samples doesn't create duplicates. 0 is reserved for "no dictionary".
+    pub fn new(data: Vec<u8>) -> Self {
+        let digest = blake3::hash(&data);
+        let id = u32::from_le_bytes(digest.as_bytes()[..4].try_into().unwrap()).max(1);
+        Dictionary { id, data }
+    }
+}
+
+/// How a chunk's payload is encoded on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Stored as-is (incompressible data such as JPEG, MP4 or zip members)
+    None,
+    Zstd,
+    /// Much faster than zstd at a lower ratio; meant for hot data
+    Lz4,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Codec (and zstd level) applied to newly written chunks.
+/// Written as `zstd`, `zstd:<level>`, `lz4` or `none`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionPolicy {
+    pub codec: Codec,
+    /// zstd compression level (ignored by the other codecs)
+    pub level: i32,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        // Level 3 is zstd's own default
+        CompressionPolicy { codec: Codec::Zstd, level: 3 }
+    }
+}
+
+impl fmt::Display for CompressionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.codec {
+            Codec::None => f.write_str("none"),
+            Codec::Zstd => write!(f, "zstd:{}", self.level),
+            Codec::Lz4 => f.write_str("lz4"),
+        }
+    }
+}
+
+impl FromStr for CompressionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), level) {
+            ("none", None) => Ok(CompressionPolicy { codec: Codec::None, level: 0 }),
+            ("lz4", None) => Ok(CompressionPolicy { codec: Codec::Lz4, level: 0 }),
+            ("zstd", None) => Ok(CompressionPolicy::default()),
+            ("zstd", Some(level)) => {
+                let level: i32 = level
+                    .parse()
+                    .map_err(|_| format!("Invalid zstd level '{}'", level))?;
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(format!("zstd level {} is out of range", level));
+                }
+                Ok(CompressionPolicy { codec: Codec::Zstd, level })
+            }
+            _ => Err(format!("Unknown compression '{}' (expected zstd[:level], lz4 or none)", s)),
+        }
+    }
+}
+
+/// Compresses `data` under `policy` and prepends the chunk header.
+/// Falls back to storing raw bytes when compression saves less than
+/// `min_savings_percent`, so already-compressed data doesn't grow.
+///
+/// With a `dictionary`, zstd chunks are compressed against it and its ID is
+/// recorded in the header; the other codecs ignore it.
+pub fn encode_chunk(
+    data: &[u8],
+    policy: CompressionPolicy,
+    min_savings_percent: u8,
+    dictionary: Option<&Dictionary>
+) -> Result<Vec<u8>, Error> {
+    let dictionary = dictionary.filter(|_| policy.codec == Codec::Zstd);
+    let compressed = match (policy.codec, dictionary) {
+        (Codec::None, _) => None,
+        (Codec::Zstd, Some(dict)) => {
+            let mut encoder = zstd::Encoder::with_dictionary(Vec::new(), policy.level, &dict.data)?;
+            encoder.write_all(data)?;
+            Some(encoder.finish()?)
+        }
+        (Codec::Zstd, None) => Some(zstd::encode_all(data, policy.level)?),
+        (Codec::Lz4, _) => Some(lz4_flex::compress_prepend_size(data)),
+    };
+
+    // Per-chunk decision: keep the compressed form only if it pays off
+    let max_len = (data.len() as u64) * (100 - (min_savings_percent.min(100) as u64)) / 100;
+    let (codec, dict_id, payload) = match compressed {
+        Some(bytes) if (bytes.len() as u64) <= max_len && bytes.len() < data.len() => {
+            (policy.codec, dictionary.map(|d| d.id), bytes)
+        }
+        _ => (Codec::None, None, data.to_vec()),
+    };
+
+    let mut stored = Vec::with_capacity(DICT_HEADER_LEN + payload.len());
+    stored.extend_from_slice(CHUNK_MAGIC);
+    match dict_id {
+        Some(id) => {
+            stored.push(2);
+            stored.push(codec.to_byte());
+            stored.extend_from_slice(&id.to_le_bytes());
+        }
+        None => {
+            stored.push(1);
+            stored.push(codec.to_byte());
+        }
+    }
+    stored.extend_from_slice(&payload);
+    Ok(stored)
+}
+
+/// The dictionary a stored chunk needs for decoding, if any
+pub fn dictionary_id(stored: &[u8]) -> Result<Option<u32>, Error> {
+    Ok(parse_header(stored)?.and_then(|header| header.dict_id))
+}
+
+/// Decodes a stored chunk written under any policy, including the
+/// header-less zstd chunks from before adaptive compression.
+/// `dictionary` must be the one named by `dictionary_id` (if any).
+pub fn decode_chunk(stored: &[u8], dictionary: Option<&Dictionary>) -> Result<Vec<u8>, Error> {
+    let Some(header) = parse_header(stored)? else {
+        return zstd::decode_all(stored);
+    };
+    match (header.codec, header.dict_id) {
+        (Codec::None, _) => Ok(header.payload.to_vec()),
+        (Codec::Zstd, Some(id)) => {
+            let dict = dictionary.filter(|d| d.id == id).ok_or_else(||
+                Error::new(ErrorKind::NotFound, format!("Dictionary {:08x} not available", id))
+            )?;
+            let mut decoder = zstd::Decoder::with_dictionary(header.payload, &dict.data)?;
+            let mut raw = Vec::new();
+            decoder.read_to_end(&mut raw)?;
+            Ok(raw)
+        }
+        (Codec::Zstd, None) => zstd::decode_all(header.payload),
+        (Codec::Lz4, _) => {
+            lz4_flex::decompress_size_prepended(header.payload).map_err(|e|
+                Error::new(ErrorKind::InvalidData, format!("LZ4 decode failed: {}", e))
+            )
+        }
+    }
+}
+
+struct ChunkHeader<'a> {
+    codec: Codec,
+    dict_id: Option<u32>,
+    payload: &'a [u8],
+}
+
+fn parse_header(stored: &[u8]) -> Result<Option<ChunkHeader<'_>>, Error> {
+    if !stored.starts_with(CHUNK_MAGIC) {
+        return Ok(None);
+    }
+    let truncated = || Error::new(ErrorKind::InvalidData, "Truncated chunk header");
+    if stored.len() < HEADER_LEN {
+        return Err(truncated());
+    }
+    let codec = Codec::from_byte(stored[CHUNK_MAGIC.len() + 1]).ok_or_else(||
+        Error::new(ErrorKind::InvalidData, "Unknown chunk codec")
+    )?;
+    match stored[CHUNK_MAGIC.len()] {
+        1 => Ok(Some(ChunkHeader { codec, dict_id: None, payload: &stored[HEADER_LEN..] })),
+        2 => {
+            if stored.len() < DICT_HEADER_LEN {
+                return Err(truncated());
+            }
+            let id = u32::from_le_bytes(stored[HEADER_LEN..DICT_HEADER_LEN].try_into().unwrap());
+            Ok(Some(ChunkHeader { codec, dict_id: Some(id), payload: &stored[DICT_HEADER_LEN..] }))
+        }
+        version => {
+            Err(Error::new(ErrorKind::InvalidData, format!("Unsupported chunk format v{}", version)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    fn stored_codec(stored: &[u8]) -> Codec {
+        parse_header(stored).unwrap().map_or(Codec::Zstd, |header| header.codec)
+    }
+
+    #[test]
+    fn test_incompressible_data_is_stored_raw() {
+        let data = noise(8192, 0);
+        let stored = encode_chunk(&data, CompressionPolicy::default(), 10, None).unwrap();
+        assert_eq!(stored_codec(&stored), Codec::None);
+        assert_eq!(stored.len(), data.len() + HEADER_LEN);
+        assert_eq!(decode_chunk(&stored, None).unwrap(), data);
+    }
+
+    #[test]
+    fn test_every_codec_round_trips() {
+        let data = b"Restless rust rusts fast. ".repeat(200);
+        for policy in ["zstd", "zstd:19", "lz4", "none"] {
+            let policy: CompressionPolicy = policy.parse().unwrap();
+            let stored = encode_chunk(&data, policy, 10, None).unwrap();
+            assert_eq!(stored_codec(&stored), policy.codec, "policy {}", policy);
+            assert_eq!(decode_chunk(&stored, None).unwrap(), data, "policy {}", policy);
+        }
+    }
+
+    #[test]
+    fn test_legacy_headerless_chunks_decode() {
+        let data = b"Written before chunk headers existed".to_vec();
+        let legacy = zstd::encode_all(&data[..], 3).unwrap();
+        assert_eq!(stored_codec(&legacy), Codec::Zstd);
+        assert_eq!(decode_chunk(&legacy, None).unwrap(), data);
+    }
+
+    #[test]
+    fn test_policy_parsing() {
+        assert_eq!("zstd:7".parse::<CompressionPolicy>().unwrap().level, 7);
+        assert_eq!("LZ4".parse::<CompressionPolicy>().unwrap().codec, Codec::Lz4);
+        assert!("zstd:999".parse::<CompressionPolicy>().is_err());
+        assert!("brotli".parse::<CompressionPolicy>().is_err());
+        assert_eq!(CompressionPolicy::default().to_string(), "zstd:3");
+    }
+
+    #[test]
+    fn test_dictionary_chunks_record_their_dictionary() {
+        // Many small, similar JSON records: the case dictionaries exist for
+        let samples: Vec<Vec<u8>> = (0..500)
+            .map(|i| format!(r#"{{"id":{},"user":"user{}","status":"active","tags":["a","b"]}}"#, i, i % 7).into_bytes())
+            .collect();
+        let dict = Dictionary::new(zstd::dict::from_samples(&samples, 4096).unwrap());
+
+        let data = br#"{"id":9001,"user":"user3","status":"active","tags":["a","b"]}"#;
+        let stored = encode_chunk(data, CompressionPolicy::default(), 0, Some(&dict)).unwrap();
+        assert_eq!(dictionary_id(&stored).unwrap(), Some(dict.id));
+        assert_eq!(decode_chunk(&stored, Some(&dict)).unwrap(), data);
+
+        // Without the dictionary the chunk can't be decoded
+        assert!(decode_chunk(&stored, None).is_err());
+
+        // Chunks written without a dictionary keep the short v1 header
+        let plain = encode_chunk(data, CompressionPolicy::default(), 0, None).unwrap();
+        assert_eq!(dictionary_id(&plain).unwrap(), None);
+        assert_eq!(decode_chunk(&plain, Some(&dict)).unwrap(), data);
+    }
+}
@@ -0,0 +1,278 @@
+// src/export.rs
+use crate::file_manager::FileManager;
+use crate::quota::normalize_prefix;
+use crate::recipe::{ FileKind, FileMeta, FileRecipe };
+use std::ffi::{ CString, OsStr };
+use std::fs::{ self, File, OpenOptions };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{ FileExt, PermissionsExt };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::Mutex;
+
+/// What `export_tree` does about something already at a destination path
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Existing {
+    /// Refuse the whole export before anything is written
+    #[default]
+    Fail,
+    /// Leave it alone and don't restore that entry
+    Skip,
+    /// Replace it (a directory in the way of a file is skipped, never deleted)
+    Overwrite,
+}
+
+/// How `export_tree` restores entries (`better-fs export`)
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub existing: Existing,
+    /// Chunk fetch threads (0 means one per CPU)
+    pub threads: usize,
+}
+
+/// Outcome of `export_tree`
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Content bytes of the files restored
+    pub bytes: u64,
+    /// "path: reason" for every entry that was left out
+    pub skipped: Vec<String>,
+}
+
+// One chunk to fetch and write at `offset` of `files[file]`
+struct ChunkJob<'a> {
+    file: usize,
+    offset: u64,
+    hash: &'a str,
+}
+
+/// Restores `path` (a file, or a directory with everything below it; "" is
+/// the whole repository) into the host directory `dest`, the way `cp -a`
+/// would: `docs` lands in `dest/docs`. Chunks are fetched in parallel and
+/// checked against their hash before they are written; holes stay sparse.
+pub fn export_tree(manager: &FileManager, path: &str, dest: &Path, options: &ExportOptions) -> Result<ExportReport, String> {
+    let path = normalize_prefix(path);
+    let entries = manager.list_subtree(&path)?;
+    if entries.is_empty() && !path.is_empty() {
+        return Err(format!("'{}' not found", path));
+    }
+    let base = match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() => dest.join(name),
+        _ => dest.to_path_buf(),
+    };
+    let target = |entry: &str| -> PathBuf {
+        let rest = entry[path.len()..].trim_start_matches('/');
+        if rest.is_empty() { base.clone() } else { base.join(rest) }
+    };
+
+    // 1. Decide what to restore; nothing is touched if that fails
+    let mut report = ExportReport::default();
+    let mut restore: Vec<(PathBuf, &FileRecipe)> = Vec::new();
+    // Files to overwrite, removed only once every entry has been checked
+    let mut replace: Vec<PathBuf> = Vec::new();
+    for (entry, recipe) in &entries {
+        let host = target(entry);
+        match fs::symlink_metadata(&host) {
+            Err(_) => {}
+            Ok(found) if recipe.kind == FileKind::Directory => {
+                if !found.is_dir() {
+                    return Err(format!("{}: exists and is not a directory", host.display()));
+                }
+            }
+            Ok(_) if options.existing == Existing::Fail => {
+                return Err(format!("{} already exists (use --overwrite or --skip-existing)", host.display()));
+            }
+            Ok(_) if options.existing == Existing::Skip => {
+                report.skipped.push(format!("{}: already exists", host.display()));
+                continue;
+            }
+            Ok(found) if found.is_dir() => {
+                report.skipped.push(format!("{}: a directory is in the way", host.display()));
+                continue;
+            }
+            Ok(_) => replace.push(host.clone()),
+        }
+        restore.push((host, recipe));
+    }
+    for host in &replace {
+        fs::remove_file(host).map_err(|e| format!("{}: {}", host.display(), e))?;
+    }
+
+    // 2. Directories and symlinks; files are created at their full size, so
+    // the chunks can be written in any order and holes are never written.
+    // They are closed straight away: a large subtree would run out of
+    // descriptors if every file stayed open until its chunks were in.
+    fs::create_dir_all(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    let mut files: Vec<(&Path, &FileRecipe)> = Vec::new();
+    for (host, recipe) in &restore {
+        let failed = |e: std::io::Error| format!("{}: {}", host.display(), e);
+        match recipe.kind {
+            FileKind::Directory => {
+                fs::create_dir_all(host).map_err(failed)?;
+                report.directories += 1;
+            }
+            FileKind::Symlink => {
+                let link = manager.read_recipe_range(recipe, 0, recipe.file_size)?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&link), host).map_err(failed)?;
+                report.symlinks += 1;
+            }
+            FileKind::File => {
+                let file = OpenOptions::new().write(true).create(true).truncate(true).open(host).map_err(failed)?;
+                file.set_len(recipe.file_size).map_err(failed)?;
+                files.push((host, recipe));
+            }
+        }
+    }
+
+    // 3. Every stored chunk of every file, spread over the threads
+    let jobs: Vec<ChunkJob> = files
+        .iter()
+        .enumerate()
+        .flat_map(|(file, (_, recipe))| {
+            let mut offset = 0;
+            recipe.chunks.iter().filter_map(move |chunk| {
+                let job = ChunkJob { file, offset, hash: &chunk.hash };
+                offset += chunk.size;
+                (!chunk.is_hole()).then_some(job)
+            })
+        })
+        .collect();
+    let threads = match options.threads {
+        0 => std::thread::available_parallelism().map_or(4, |n| n.get()),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let error: Mutex<Option<String>> = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(jobs.len()) {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    let (host, _) = files[job.file];
+                    let written = manager.read_verified_chunk(job.hash).and_then(|data| {
+                        OpenOptions::new()
+                            .write(true)
+                            .open(host)
+                            .and_then(|file| file.write_all_at(&data, job.offset))
+                            .map_err(|e| e.to_string())
+                    });
+                    if let Err(e) = written {
+                        stop.store(true, Ordering::Relaxed);
+                        error.lock().unwrap().get_or_insert(format!("{}: {}", host.display(), e));
+                    }
+                }
+            });
+        }
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    for (host, recipe) in files {
+        File::open(host).and_then(|file| file.sync_all()).map_err(|e| format!("{}: {}", host.display(), e))?;
+        report.files += 1;
+        report.bytes += recipe.file_size;
+    }
+
+    // 4. Modes and mtimes last, children before their directory: writing into
+    // a directory changes its mtime, and a read-only one couldn't be filled
+    for (host, recipe) in restore.iter().rev() {
+        if recipe.meta.mode != 0 && recipe.kind != FileKind::Symlink {
+            fs::set_permissions(host, fs::Permissions::from_mode(recipe.meta.mode))
+                .map_err(|e| format!("{}: {}", host.display(), e))?;
+        }
+        set_mtime(host, &recipe.meta).map_err(|e| format!("{}: {}", host.display(), e))?;
+    }
+    Ok(report)
+}
+
+// utimensat(2) without following symlinks, so links get their own mtime.
+// The access time is left as it is.
+fn set_mtime(path: &Path, meta: &FileMeta) -> Result<(), std::io::Error> {
+    if meta.mtime().is_none() {
+        return Ok(());
+    }
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: meta.mtime_secs as libc::time_t, tv_nsec: meta.mtime_nanos as libc::c_long },
+    ];
+    // SAFETY: `path` is NUL-terminated and `times` holds the two entries utimensat reads
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::{ import_tree, ImportOptions };
+    use crate::test_util::{ noise, reset };
+    use std::os::unix::fs::{ symlink, MetadataExt };
+
+    #[test]
+    fn test_export_round_trips_an_import() {
+        let (src, out, db_path) = ("./test_export_src", "./test_export_out", "./test_db_export");
+        reset(src);
+        reset(out);
+        reset(db_path);
+
+        fs::create_dir_all(format!("{}/bin", src)).unwrap();
+        let big = noise(200_000, 0);
+        let mut sparse = vec![0u8; 300_000];
+        sparse[..5].copy_from_slice(b"start");
+        fs::write(format!("{}/bin/big.dat", src), &big).unwrap();
+        fs::write(format!("{}/bin/sparse.img", src), &sparse).unwrap();
+        fs::write(format!("{}/bin/tool", src), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(format!("{}/bin/tool", src), fs::Permissions::from_mode(0o751)).unwrap();
+        fs::set_permissions(format!("{}/bin", src), fs::Permissions::from_mode(0o700)).unwrap();
+        symlink("bin/tool", format!("{}/run", src)).unwrap();
+
+        let manager = FileManager::new(db_path);
+        let options = ImportOptions { prefix: "proj".to_string(), ..ImportOptions::default() };
+        import_tree(&manager, Path::new(src), &options).unwrap();
+
+        // 1. The subtree comes back under its own name with content, modes and links
+        let report = export_tree(&manager, "/proj", Path::new(out), &ExportOptions { existing: Existing::Fail, threads: 3 }).unwrap();
+        assert_eq!((report.files, report.directories, report.symlinks), (3, 2, 1));
+        assert_eq!(fs::read(format!("{}/proj/bin/big.dat", out)).unwrap(), big);
+        assert_eq!(fs::read(format!("{}/proj/bin/sparse.img", out)).unwrap(), sparse);
+        assert_eq!(fs::read_link(format!("{}/proj/run", out)).unwrap(), Path::new("bin/tool"));
+        let tool = fs::metadata(format!("{}/proj/bin/tool", out)).unwrap();
+        let original = fs::metadata(format!("{}/bin/tool", src)).unwrap();
+        assert_eq!(tool.mode() & 0o7777, 0o751);
+        assert_eq!((tool.mtime(), tool.mtime_nsec()), (original.mtime(), original.mtime_nsec()));
+        assert_eq!(fs::metadata(format!("{}/proj/bin", out)).unwrap().mode() & 0o7777, 0o700);
+
+        // 2. Existing files: refused by default, then skipped or replaced
+        fs::write(format!("{}/tool", out), b"local edits").unwrap();
+        let single = |existing| export_tree(&manager, "proj/bin/tool", Path::new(out), &ExportOptions { existing, threads: 1 });
+        assert!(single(Existing::Fail).unwrap_err().contains("already exists"));
+        assert_eq!(single(Existing::Skip).unwrap().skipped.len(), 1);
+        assert_eq!(fs::read(format!("{}/tool", out)).unwrap(), b"local edits");
+        assert_eq!(single(Existing::Overwrite).unwrap().files, 1);
+        assert_eq!(fs::read(format!("{}/tool", out)).unwrap(), b"#!/bin/sh\n");
+        assert!(export_tree(&manager, "missing", Path::new(out), &ExportOptions::default()).is_err());
+
+        // 3. Nothing is replaced when a later entry can't be restored
+        manager.write_file("proj/a.txt", b"stored").unwrap();
+        fs::write(format!("{}/proj/a.txt", out), b"host copy").unwrap();
+        fs::set_permissions(format!("{}/proj/bin", out), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(format!("{}/proj/bin", out)).unwrap();
+        fs::write(format!("{}/proj/bin", out), b"not a directory").unwrap();
+        let overwrite = ExportOptions { existing: Existing::Overwrite, threads: 1 };
+        assert!(export_tree(&manager, "proj", Path::new(out), &overwrite).unwrap_err().contains("not a directory"));
+        assert_eq!(fs::read(format!("{}/proj/a.txt", out)).unwrap(), b"host copy");
+
+        fs::set_permissions(format!("{}/bin", src), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(out).unwrap();
+        fs::remove_dir_all(db_path).unwrap();
+    }
+}
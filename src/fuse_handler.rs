@@ -1,29 +1,54 @@
 // src/fuse_handler.rs
-use crate::file_manager::FileManager;
-use crate::file_manager::FileKind;
+use crate::file_manager::{ FileManager, RenameMode, WriteError };
+use crate::locks::{ Lock, LockTable };
+use crate::quota;
+use crate::prefetch::ReadAhead;
+use crate::recipe::{ ChunkOffsets, FileKind, FileMeta, FileRecipe };
+use crate::worker_pool::WorkerPool;
 use fuser::{
     FileAttr,
     FileType,
     Filesystem,
+    KernelConfig,
     ReplyAttr,
     ReplyData,
     ReplyDirectory,
+    ReplyDirectoryPlus,
     ReplyEntry,
+    ReplyLock,
+    ReplyLseek,
     ReplyWrite,
     ReplyCreate,
     ReplyEmpty,
     ReplyOpen,
+    ReplyStatfs,
+    ReplyXattr,
     Request,
 };
+use fuser::consts::{ FUSE_DO_READDIRPLUS, FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS };
 use libc::ENOENT; // Removed EIO as it was unused
 use std::ffi::OsStr;
-use std::time::{ Duration, UNIX_EPOCH, SystemTime };
+use std::fmt;
+use std::str::FromStr;
+use std::time::{ Duration, SystemTime };
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet, VecDeque };
 use std::hash::{ Hash, Hasher };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use std::sync::{ Arc, Mutex, RwLock, Weak };
+use std::time::Instant;
 
 const TTL: Duration = Duration::from_secs(1);
 
+// Block size statfs reports sizes in
+const STATFS_BLOCK_SIZE: u32 = 4096;
+const MAX_NAME_LENGTH: u32 = 255;
+
+// Read-only xattr on the mount root reporting chunk cache counters:
+//   getfattr -n user.betterfs.cache_stats /mnt/point
+const CACHE_STATS_XATTR: &str = "user.betterfs.cache_stats";
+
 // HELPER: Turn a String ("file.txt") into a Number (Inode)
 fn calculate_inode(filename: &str) -> u64 {
     let mut s = DefaultHasher::new();
@@ -31,21 +56,161 @@ fn calculate_inode(filename: &str) -> u64 {
     s.finish()
 }
 
+// HELPER: "dir" + "file.txt" -> "dir/file.txt" (the root is "")
+fn child_path(parent_path: &str, name: &str) -> String {
+    if parent_path.is_empty() { name.to_string() } else { format!("{}/{}", parent_path, name) }
+}
+
+// Directory offsets ("cookies") the kernel hands back to resume a listing.
+// 1 and 2 are "." and ".."; a child's cookie is a hash of its name, so it
+// keeps meaning the same position while other entries come and go.
+const DOT_COOKIE: i64 = 1;
+const DOTDOT_COOKIE: i64 = 2;
+
+fn dir_cookie(name: &str) -> i64 {
+    // Offsets are signed, and 0 means "from the start"
+    ((calculate_inode(name) >> 1) as i64).max(DOTDOT_COOKIE + 1)
+}
+
+fn file_type(kind: &FileKind) -> FileType {
+    match kind {
+        FileKind::File => FileType::RegularFile,
+        FileKind::Directory => FileType::Directory,
+        FileKind::Symlink => FileType::Symlink,
+    }
+}
+
+/// When the contents of a file open for writing are committed to storage as
+/// a recipe. `fsync` always commits, whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritebackPolicy {
+    /// Every close(2) commits, and reports a failed commit (the default)
+    OnClose,
+    /// Nothing is committed until fsync, or until the last handle is released
+    OnFsync,
+    /// Like `OnFsync`, plus dirty files are committed at this interval
+    Periodic(Duration),
+}
+
+impl fmt::Display for WritebackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WritebackPolicy::OnClose => f.write_str("on-close"),
+            WritebackPolicy::OnFsync => f.write_str("on-fsync"),
+            WritebackPolicy::Periodic(interval) => write!(f, "periodic:{}", interval.as_secs()),
+        }
+    }
+}
+
+/// Parses "on-close", "on-fsync" or "periodic:<seconds>"
+impl FromStr for WritebackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "on-close" => Ok(WritebackPolicy::OnClose),
+            None if s == "on-fsync" => Ok(WritebackPolicy::OnFsync),
+            Some(("periodic", secs)) => {
+                let secs: u64 = secs.parse().map_err(|_| format!("Invalid interval '{}'", secs))?;
+                if secs == 0 {
+                    return Err("The writeback interval must be at least 1 second".to_string());
+                }
+                Ok(WritebackPolicy::Periodic(Duration::from_secs(secs)))
+            }
+            _ => Err(format!("Unknown writeback policy '{}' (on-close, on-fsync, periodic:<secs>)", s)),
+        }
+    }
+}
+
+/// Mount-time settings for `BetterFS`
+#[derive(Debug, Clone, Copy)]
+pub struct FsConfig {
+    pub writeback: WritebackPolicy,
+    /// Refuse every change with EROFS, before it reaches the FileManager
+    pub read_only: bool,
+    /// Owner every file and directory is reported with
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        FsConfig { writeback: WritebackPolicy::OnClose, read_only: false, uid: 1000, gid: 1000 }
+    }
+}
+
+// One line of a directory listing
+struct DirEntry {
+    cookie: i64,
+    ino: u64,
+    name: String,
+    size: u64,
+    kind: FileKind,
+    meta: FileMeta,
+}
+
 // Struct to hold a file being written in RAM
 struct WriteBuffer {
     filename: String,
     data: Vec<u8>,
+    // Writable handles still open on it; the buffer goes away with the last one
+    handles: usize,
+    // Changed since it was loaded or last committed
+    dirty: bool,
 }
 
-pub struct BetterFS {
-    pub manager: FileManager,
+// A file being read from storage: its recipe (decoded once, not per read)
+// and the sequential-access tracker that drives prefetch
+struct ReadState {
+    recipe: Arc<FileRecipe>,
+    // Every read looks up its first chunk; this keeps that a binary search
+    offsets: ChunkOffsets,
+    read_ahead: Mutex<ReadAhead>,
+}
+
+/// Everything the request handlers share. Each map has its own lock, and a
+/// file being written has a lock of its own, so a long `release` flush only
+/// holds up requests for that same file.
+struct FsState {
+    manager: Arc<FileManager>,
+    inode_map: RwLock<HashMap<u64, String>>,
     // Memory buffer for open files: Inode -> Data
-    open_files: HashMap<u64, WriteBuffer>,
-    inode_map: HashMap<u64, String>,
+    open_files: RwLock<HashMap<u64, Arc<Mutex<WriteBuffer>>>>,
+    readers: Mutex<HashMap<u64, Arc<ReadState>>>,
+    // File handles that hold a reference on a WriteBuffer
+    write_handles: Mutex<HashSet<u64>>,
+    next_fh: AtomicU64,
+    // Decompresses upcoming chunks into the cache while the reader works
+    prefetch_pool: WorkerPool,
+    config: FsConfig,
+    // fcntl and flock locks; blocked F_SETLKW replies wait in here
+    locks: Mutex<LockTable<LockWaiter>>,
+    // How far each path may grow under its quotas, as of one quota generation
+    // of the manager; growing writes check this instead of reading sled
+    quota_headroom: Mutex<(u64, HashMap<String, Option<u64>>)>,
+}
+
+/// Answers a SETLK: at once, or for a blocked SETLKW once the lock is
+/// granted (Ok) or the wait is cancelled
+type LockWaiter = Box<dyn FnOnce(Result<(), libc::c_int>) + Send>;
+
+/// A request waiting for the one ahead of it on the same file handle
+type HandleJob = Box<dyn FnOnce(&FsState) + Send>;
+
+/// The FUSE session calls into this from a single thread; every request is
+/// handed to a worker pool so slow operations don't serialize the mount.
+pub struct BetterFS {
+    state: Arc<FsState>,
+    workers: WorkerPool,
+    // Requests per file handle still to run, in arrival order; a handle has
+    // an entry only while a worker is draining it
+    handle_queues: Arc<Mutex<HashMap<u64, VecDeque<HandleJob>>>>,
+    // Dropping this stops the periodic writeback thread, if there is one
+    _writeback_stop: Option<mpsc::Sender<()>>,
 }
 
 impl BetterFS {
-    pub fn new(manager: FileManager) -> Self {
+    pub fn new(manager: FileManager, config: FsConfig) -> Self {
         let mut inode_map = HashMap::new();
 
         // 1. Initialize Root (Inode 1 is empty path "")
@@ -60,45 +225,249 @@ impl BetterFS {
         }
         println!("FUSE: Restored {} inodes.", inode_map.len());
 
+        let state = FsState {
+            manager: Arc::new(manager),
+            inode_map: RwLock::new(inode_map),
+            open_files: RwLock::new(HashMap::new()),
+            readers: Mutex::new(HashMap::new()),
+            write_handles: Mutex::new(HashSet::new()),
+            next_fh: AtomicU64::new(1),
+            prefetch_pool: WorkerPool::with_default_size("prefetch"),
+            config,
+            locks: Mutex::new(LockTable::new()),
+            quota_headroom: Mutex::new((0, HashMap::new())),
+        };
+        let state = Arc::new(state);
+        let writeback_stop = match config.writeback {
+            WritebackPolicy::Periodic(interval) => Some(spawn_writeback(Arc::downgrade(&state), interval)),
+            _ => None,
+        };
         BetterFS {
-            manager,
-            open_files: HashMap::new(),
-            inode_map,
+            state,
+            workers: WorkerPool::with_default_size("fuse"),
+            handle_queues: Arc::new(Mutex::new(HashMap::new())),
+            _writeback_stop: writeback_stop,
+        }
+    }
+
+    // Runs a handler on the worker pool
+    fn dispatch<F: FnOnce(&FsState) + Send + 'static>(&self, handler: F) {
+        let state = self.state.clone();
+        self.workers.execute(move || handler(&state));
+    }
+
+    // Runs a handler on the worker pool once every earlier request on the
+    // same file handle is done, so e.g. a write can't land after the release
+    // the kernel sent behind it
+    fn dispatch_for_handle<F: FnOnce(&FsState) + Send + 'static>(&self, fh: u64, handler: F) {
+        {
+            let mut queues = self.handle_queues.lock().unwrap();
+            if let Some(queue) = queues.get_mut(&fh) {
+                queue.push_back(Box::new(handler));
+                return;
+            }
+            queues.insert(fh, VecDeque::new());
         }
+        let queues = self.handle_queues.clone();
+        self.dispatch(move |fs| {
+            let mut job: HandleJob = Box::new(handler);
+            loop {
+                job(fs);
+                let mut queues = queues.lock().unwrap();
+                match queues.get_mut(&fh).and_then(VecDeque::pop_front) {
+                    Some(next) => job = next,
+                    None => {
+                        queues.remove(&fh);
+                        break;
+                    }
+                }
+            }
+        });
     }
 }
 
-impl Filesystem for BetterFS {
+/// Names arrive as raw bytes but paths are stored as strings; a name that
+/// isn't UTF-8 is refused rather than stored mangled
+fn utf8_name(name: &OsStr) -> Result<String, libc::c_int> {
+    name.to_str().map(str::to_owned).ok_or(libc::EINVAL)
+}
+
+impl FsState {
+    fn path_of(&self, ino: u64) -> Option<String> {
+        self.inode_map.read().unwrap().get(&ino).cloned()
+    }
+
+    fn open_buffer(&self, ino: u64) -> Option<Arc<Mutex<WriteBuffer>>> {
+        self.open_files.read().unwrap().get(&ino).cloned()
+    }
+
+    /// EDQUOT if growing `path` to `new_size` would break a quota. The answer
+    /// is cached until a commit or limit change moves the quota generation.
+    fn check_growth(&self, path: &str, new_size: u64) -> Result<(), libc::c_int> {
+        let generation = self.manager.quota_generation();
+        let cached = {
+            let mut cache = self.quota_headroom.lock().unwrap();
+            if cache.0 != generation {
+                *cache = (generation, HashMap::new());
+            }
+            cache.1.get(path).copied()
+        };
+        // Read outside the lock; a commit racing with this bumps the
+        // generation, so a stale answer is dropped on the next check
+        let headroom = cached.unwrap_or_else(|| {
+            let headroom = self.manager.quota_headroom(path);
+            let mut cache = self.quota_headroom.lock().unwrap();
+            if cache.0 == generation {
+                cache.1.insert(path.to_string(), headroom);
+            }
+            headroom
+        });
+        match headroom {
+            Some(max_size) if new_size > max_size => Err(libc::EDQUOT),
+            _ => Ok(()),
+        }
+    }
+
+    // HELPER: Attributes of a file or directory as stored in the database.
+    // Imported entries keep their mode and mtime; the rest get defaults.
+    fn stored_attr(&self, ino: u64, size: u64, kind: &FileKind, meta: &FileMeta) -> FileAttr {
+        let perm = match kind {
+            _ if meta.mode != 0 => meta.mode as u16,
+            FileKind::File => 0o644,
+            FileKind::Directory => 0o755,
+            FileKind::Symlink => 0o777,
+        };
+        let mtime = meta.mtime().unwrap_or_else(SystemTime::now);
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: SystemTime::now(),
+            kind: file_type(kind),
+            perm,
+            nlink: 1,
+            uid: self.config.uid,
+            gid: self.config.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
+    fn new_handle(&self) -> u64 {
+        self.next_fh.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Takes a reference on the file's write buffer, loading it from storage
+    /// if no other handle has it open. Never waits on a buffer while holding
+    /// the map lock (`release` does the reverse).
+    fn acquire_buffer(&self, ino: u64, filename: &str) -> Result<(), String> {
+        loop {
+            if let Some(shared) = self.open_buffer(ino) {
+                let mut buffer = shared.lock().unwrap();
+                // With no handles left it's still ours only if its final
+                // commit failed and left it in the map to retry
+                if buffer.handles > 0 || self.open_buffer(ino).is_some_and(|current| Arc::ptr_eq(&current, &shared)) {
+                    buffer.handles += 1;
+                    return Ok(());
+                }
+                // Its last handle was released while we waited; it's already
+                // out of the map, and storage has the flushed contents
+                continue;
+            }
+
+            let data = self.manager.read_file(filename)?;
+            let mut open_files = self.open_files.write().unwrap();
+            if open_files.contains_key(&ino) {
+                continue; // Another open got there first; share its buffer
+            }
+            let buffer = WriteBuffer { filename: filename.to_string(), data, handles: 1, dirty: false };
+            open_files.insert(ino, Arc::new(Mutex::new(buffer)));
+            return Ok(());
+        }
+    }
+
+    /// Stores a dirty buffer as the file's recipe. Errors come back as the
+    /// errno to reply with; the buffer stays dirty so a later commit retries.
+    fn commit_buffer(&self, ino: u64, buffer: &mut WriteBuffer) -> Result<(), libc::c_int> {
+        if !buffer.dirty {
+            return Ok(());
+        }
+        println!("FUSE: Flushing '{}' to Storage...", buffer.filename);
+        if let Err(e) = self.manager.write_file(&buffer.filename, &buffer.data) {
+            eprintln!("FUSE: Flushing '{}' failed: {}", buffer.filename, e);
+            return Err(errno(&e));
+        }
+        buffer.dirty = false;
+        self.readers.lock().unwrap().remove(&ino);
+        Ok(())
+    }
+
+    /// Periodic writeback and unmount: commits every dirty buffer. Failures
+    /// are only logged here; the next fsync or close reports them. A closed
+    /// buffer whose final commit failed leaves the map once this stores it.
+    fn commit_dirty(&self) {
+        let open: Vec<(u64, Arc<Mutex<WriteBuffer>>)> = self.open_files
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(ino, buffer)| (*ino, buffer.clone()))
+            .collect();
+        for (ino, buffer) in open {
+            let mut buffer = buffer.lock().unwrap();
+            if self.commit_buffer(ino, &mut buffer).is_ok() && buffer.handles == 0 {
+                self.open_files.write().unwrap().remove(&ino);
+            }
+        }
+    }
+
+    /// Queues the chunks the read-ahead window asks for on the prefetch pool
+    fn schedule_prefetch(&self, state: &ReadState, offset: u64, size: u64) {
+        let range = state.read_ahead
+            .lock()
+            .unwrap()
+            .on_read(&state.recipe, &state.offsets, offset, size, Instant::now());
+        for chunk in state.recipe.chunks[range].iter().filter(|chunk| !chunk.is_hole()) {
+            let manager = self.manager.clone();
+            let hash = chunk.hash.clone();
+            self.prefetch_pool.execute(move || {
+                // A failed prefetch just means the real read hits the error itself
+                let _ = manager.prefetch_chunk(&hash);
+            });
+        }
+    }
+
     // 1. LOOKUP
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name_str = name.to_str().unwrap();
+    fn lookup(&self, parent: u64, name_str: &str, reply: ReplyEntry) {
+        match self.lookup_entry(parent, name_str) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
 
+    /// Attributes of `name_str` in directory `parent`; remembers its path
+    fn lookup_entry(&self, parent: u64, name_str: &str) -> Result<FileAttr, libc::c_int> {
         // A. Resolve Parent Path (The "Nesting" Fix)
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
         };
 
         // B. Build Full Path (e.g. "my_folder" + "/" + "inside.png")
-        let full_path = if parent_path.is_empty() {
-            name_str.to_string()
-        } else {
-            format!("{}/{}", parent_path, name_str)
-        };
+        let full_path = child_path(&parent_path, name_str);
 
         // C. Calculate Inode for THIS specific file
         let inode = calculate_inode(&full_path);
 
         // 1. Check RAM Buffer (Is it open?)
-        if let Some(buffer) = self.open_files.get(&inode) {
-            let size = buffer.data.len() as u64;
+        if let Some(buffer) = self.open_buffer(inode) {
+            let size = buffer.lock().unwrap().data.len() as u64;
             let attr = FileAttr {
                 ino: inode,
                 size,
-                blocks: (size + 511) / 512,
+                blocks: size.div_ceil(512),
                 atime: SystemTime::now(),
                 mtime: SystemTime::now(),
                 ctime: SystemTime::now(),
@@ -106,69 +475,49 @@ impl Filesystem for BetterFS {
                 kind: FileType::RegularFile, // Open buffers are usually files
                 perm: 0o644,
                 nlink: 1,
-                uid: 1000,
-                gid: 1000,
+                uid: self.config.uid,
+                gid: self.config.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
             };
             // CRITICAL: Memorize this path
-            self.inode_map.insert(inode, full_path);
-            return reply.entry(&TTL, &attr, 0);
+            self.inode_map.write().unwrap().insert(inode, full_path);
+            return Ok(attr);
         }
 
         // 2. Check Backend (Database)
-        if let Some((size, kind)) = self.manager.get_file_metadata(&full_path) {
+        if let Some((size, kind, meta)) = self.manager.stat(&full_path) {
             // CRITICAL: Memorize this path so we can find it again later!
-            self.inode_map.insert(inode, full_path);
-
-            let (file_type, perm) = match kind {
-                FileKind::File => (FileType::RegularFile, 0o644),
-                FileKind::Directory => (FileType::Directory, 0o755),
-            };
-
-            let attr = FileAttr {
-                ino: inode,
-                size,
-                blocks: (size + 511) / 512,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
-                kind: file_type,
-                perm,
-                nlink: 1,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-            reply.entry(&TTL, &attr, 0);
+            self.inode_map.write().unwrap().insert(inode, full_path);
+            Ok(self.stored_attr(inode, size, &kind, &meta))
         } else {
-            reply.error(ENOENT);
+            Err(ENOENT)
         }
     }
 
     // 2. GETATTR
-    // src/fuse_handler.rs -> getattr
+    fn getattr(&self, ino: u64, reply: ReplyAttr) {
+        match self.attr_of(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    /// Attributes of an inode: its open buffer, the root, or what's stored
+    fn attr_of(&self, ino: u64) -> Result<FileAttr, libc::c_int> {
         // 1. Resolve Inode to Path
-        let filename = match self.inode_map.get(&ino) {
-            Some(name) => name.clone(),
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+        let Some(filename) = self.path_of(ino) else {
+            return Err(ENOENT);
         };
 
         // 2. Check RAM Buffer (Files being written)
-        if let Some(buffer) = self.open_files.get(&ino) {
+        if let Some(buffer) = self.open_buffer(ino) {
+            let size = buffer.lock().unwrap().data.len() as u64;
             let attr = FileAttr {
                 ino,
-                size: buffer.data.len() as u64,
-                blocks: ((buffer.data.len() as u64) + 511) / 512,
+                size,
+                blocks: size.div_ceil(512),
                 atime: SystemTime::now(),
                 mtime: SystemTime::now(),
                 ctime: SystemTime::now(),
@@ -176,14 +525,13 @@ impl Filesystem for BetterFS {
                 kind: FileType::RegularFile,
                 perm: 0o644,
                 nlink: 1,
-                uid: 1000,
-                gid: 1000,
+                uid: self.config.uid,
+                gid: self.config.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
             };
-            reply.attr(&TTL, &attr);
-            return;
+            return Ok(attr);
         }
 
         // ===================================================================
@@ -203,153 +551,157 @@ impl Filesystem for BetterFS {
                 kind: FileType::Directory, // Root is always a Directory
                 perm: 0o755,
                 nlink: 2,
-                uid: 1000,
-                gid: 1000,
+                uid: self.config.uid,
+                gid: self.config.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
             };
-            reply.attr(&TTL, &attr);
-            return;
+            return Ok(attr);
         }
 
         // 4. Check Backend (Database)
-        if let Some((size, kind)) = self.manager.get_file_metadata(&filename) {
-            let (file_type, perm) = match kind {
-                FileKind::File => (FileType::RegularFile, 0o644),
-                FileKind::Directory => (FileType::Directory, 0o755),
-            };
-
-            let attr = FileAttr {
-                ino,
-                size,
-                blocks: (size + 511) / 512,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
-                kind: file_type,
-                perm,
-                nlink: 1,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-            reply.attr(&TTL, &attr);
+        if let Some((size, kind, meta)) = self.manager.stat(&filename) {
+            Ok(self.stored_attr(ino, size, &kind, &meta))
         } else {
             // If it's not in RAM, not Root, and not in DB -> It doesn't exist.
-            reply.error(ENOENT);
+            Err(ENOENT)
         }
     }
 
     // 3. READDIR
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory
-    ) {
-        // 1. Get the path (CLONE IT to satisfy borrow checker)
-        // We clone() so we own the string and stop borrowing 'self.inode_map'
-        let dir_path = match self.inode_map.get(&ino) {
-            Some(p) => p.clone(), // <--- FIX: Clone the string here
-            None => {
-                return reply.error(ENOENT);
-            }
+    fn readdir(&self, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        let Some(entries) = self.directory_page(ino, offset) else {
+            return reply.error(ENOENT);
         };
+        for entry in entries {
+            // A full buffer: the kernel asks again from the last cookie it got
+            if reply.add(entry.ino, entry.cookie, file_type(&entry.kind), &entry.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
 
-        if offset == 0 {
-            reply.add(1, 0, FileType::Directory, ".");
-            reply.add(1, 1, FileType::Directory, "..");
-
-            let all_files = self.manager.list_files();
-
-            for filename in all_files {
-                if filename == dir_path {
-                    continue;
-                }
-
-                // Logic to check if 'filename' is a direct child of 'dir_path'
-                let is_child = if dir_path.is_empty() {
-                    !filename.contains('/')
-                } else {
-                    if
-                        filename.starts_with(&dir_path) &&
-                        filename.chars().nth(dir_path.len()) == Some('/')
-                    {
-                        let relative_part = &filename[dir_path.len() + 1..];
-                        !relative_part.contains('/')
-                    } else {
-                        false
-                    }
-                };
+    // 3b. READDIRPLUS: READDIR plus the attributes LOOKUP would return
+    fn readdirplus(&self, ino: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        let Some(entries) = self.directory_page(ino, offset) else {
+            return reply.error(ENOENT);
+        };
+        for entry in entries {
+            // Open files report their buffered size, as in lookup
+            let size = self
+                .open_buffer(entry.ino)
+                .map_or(entry.size, |buffer| buffer.lock().unwrap().data.len() as u64);
+            let attr = self.stored_attr(entry.ino, size, &entry.kind, &entry.meta);
+            if reply.add(entry.ino, entry.cookie, &entry.name, &TTL, &attr, 0) {
+                break;
+            }
+        }
+        reply.ok();
+    }
 
-                if is_child {
-                    let child_inode = calculate_inode(&filename);
+    /// Entries of directory `ino` that come after cookie `offset`, in cookie
+    /// order, "." and ".." first. Also memorizes each child's path.
+    fn directory_page(&self, ino: u64, offset: i64) -> Option<Vec<DirEntry>> {
+        // 1. Get the path (an owned copy, so no lock is held while listing)
+        let dir_path = self.path_of(ino)?;
+        let parent_ino = match dir_path.rsplit_once('/') {
+            Some((parent, _)) => calculate_inode(parent),
+            None => 1, // Top-level entries (and the root itself) have the root as parent
+        };
 
-                    // We can safely unwrap because we know the file exists in the list
-                    if let Some((_size, kind)) = self.manager.get_file_metadata(&filename) {
-                        let file_type = match kind {
-                            FileKind::File => FileType::RegularFile,
-                            FileKind::Directory => FileType::Directory,
-                        };
+        // 2. Children, with cookies derived from their names
+        let mut entries: Vec<DirEntry> = self.manager
+            .list_directory(&dir_path)
+            .into_iter()
+            .map(|(path, size, kind, meta)| {
+                let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+                let ino = calculate_inode(&path);
+                self.inode_map.write().unwrap().insert(ino, path);
+                DirEntry { cookie: dir_cookie(&name), ino, name, size, kind, meta }
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.cookie);
+
+        let dot = |cookie, ino, name: &str| DirEntry {
+            cookie,
+            ino,
+            name: name.to_string(),
+            size: 0,
+            kind: FileKind::Directory,
+            meta: FileMeta::default(),
+        };
+        let mut page = vec![dot(DOT_COOKIE, ino, "."), dot(DOTDOT_COOKIE, parent_ino, "..")];
+        page.append(&mut entries);
 
-                        let name_only = filename.split('/').last().unwrap();
-                        let _ = reply.add(child_inode, offset + 1, file_type, name_only);
+        // 3. Resume after the last entry the kernel received
+        page.retain(|entry| entry.cookie > offset);
+        Some(page)
+    }
 
-                        // NOW this works because 'dir_path' is a clone, not a borrow
-                        self.inode_map.insert(child_inode, filename);
-                    }
-                }
-            }
+    // 4. READ (Optimized with Inode Map)
+    fn read(&self, ino: u64, offset: i64, size: u32, reply: ReplyData) {
+        match self.read_range(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
         }
-        reply.ok();
     }
 
-    // 4. READ (Optimized with Inode Map)
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData
-    ) {
+    /// Up to `size` bytes at `offset`, from the open buffer or the stored chunks
+    fn read_range(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, libc::c_int> {
         // 1. Check RAM Buffer
-        if let Some(buffer) = self.open_files.get(&ino) {
+        if let Some(buffer) = self.open_buffer(ino) {
+            let buffer = buffer.lock().unwrap();
             let start = offset as usize;
             if start < buffer.data.len() {
-                let end = std::cmp::min(start + (_size as usize), buffer.data.len());
-                reply.data(&buffer.data[start..end]);
-            } else {
-                reply.data(&[]);
+                let end = std::cmp::min(start + (size as usize), buffer.data.len());
+                return Ok(buffer.data[start..end].to_vec());
             }
-            return;
+            return Ok(Vec::new());
         }
 
         // 2. Check Backend using MAP (Fast!)
-        if let Some(filename) = self.inode_map.get(&ino) {
-            match self.manager.read_file(filename) {
-                Ok(data) => {
-                    let start = offset as usize;
-                    if start < data.len() {
-                        let end = std::cmp::min(start + (_size as usize), data.len());
-                        reply.data(&data[start..end]);
-                    } else {
-                        reply.data(&[]);
-                    }
-                }
-                Err(_) => reply.error(libc::EIO),
+        let Some(filename) = self.path_of(ino) else {
+            return Err(ENOENT);
+        };
+        let existing = self.readers.lock().unwrap().get(&ino).cloned();
+        let state = match existing {
+            Some(state) => state,
+            None => {
+                // Decode outside the lock; if two reads race, the first insert wins
+                let Ok(recipe) = self.manager.load_recipe(&filename) else {
+                    return Err(libc::EIO);
+                };
+                let state = Arc::new(ReadState {
+                    offsets: ChunkOffsets::new(&recipe),
+                    recipe: Arc::new(recipe),
+                    read_ahead: Mutex::new(ReadAhead::new()),
+                });
+                self.readers.lock().unwrap().entry(ino).or_insert(state).clone()
             }
-        } else {
-            reply.error(ENOENT);
+        };
+
+        // 3. Only decompress the chunks this range touches
+        let data = self.manager
+            .read_indexed_range(&state.recipe, &state.offsets, offset as u64, size as u64)
+            .map_err(|_| libc::EIO)?;
+        self.schedule_prefetch(&state, offset as u64, data.len() as u64);
+        Ok(data)
+    }
+
+    // 4b. READLINK: a symlink's target is its content
+    fn readlink(&self, ino: u64, reply: ReplyData) {
+        let Some(path) = self.path_of(ino) else {
+            return reply.error(ENOENT);
+        };
+        match self.manager.load_recipe(&path) {
+            Ok(recipe) if recipe.kind == FileKind::Symlink => match self.manager.read_recipe_range(&recipe, 0, recipe.file_size) {
+                Ok(target) => reply.data(&target),
+                Err(_) => reply.error(libc::EIO),
+            },
+            Ok(_) => reply.error(libc::EINVAL),
+            Err(_) => reply.error(ENOENT),
         }
     }
 
@@ -358,32 +710,30 @@ impl Filesystem for BetterFS {
     // =======================================================================
 
     // 5. CREATE (Supports Nesting)
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
-        reply: ReplyCreate
-    ) {
-        let name_str = name.to_str().unwrap();
+    fn create(&self, parent: u64, name_str: &str, flags: i32, reply: ReplyCreate) {
+        match self.create_file(parent, name_str, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
 
+    /// Starts a new file as an empty dirty buffer; returns it and a write handle
+    fn create_file(&self, parent: u64, name_str: &str, flags: i32) -> Result<(FileAttr, u64), libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
         // 1. Resolve Parent
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
-            }
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
         };
 
         // 2. Build Full Path
-        let full_path = if parent_path.is_empty() {
-            name_str.to_string()
-        } else {
-            format!("{}/{}", parent_path, name_str)
-        };
+        let full_path = child_path(&parent_path, name_str);
+        match self.manager.get_file_metadata(&full_path) {
+            Some((_, FileKind::Directory)) => return Err(libc::EISDIR),
+            Some(_) if flags & libc::O_EXCL != 0 => return Err(libc::EEXIST),
+            _ => {}
+        }
 
         let inode = calculate_inode(&full_path);
 
@@ -391,11 +741,15 @@ impl Filesystem for BetterFS {
         let buffer = WriteBuffer {
             filename: full_path.clone(),
             data: Vec::new(),
+            handles: 1,
+            dirty: true, // Even empty, it has to exist once committed
         };
-        self.open_files.insert(inode, buffer);
+        self.open_files.write().unwrap().insert(inode, Arc::new(Mutex::new(buffer)));
+        let fh = self.new_handle();
+        self.write_handles.lock().unwrap().insert(fh);
 
         // 4. Update Map immediately
-        self.inode_map.insert(inode, full_path);
+        self.inode_map.write().unwrap().insert(inode, full_path);
 
         let attr = FileAttr {
             ino: inode,
@@ -408,296 +762,1328 @@ impl Filesystem for BetterFS {
             kind: FileType::RegularFile,
             perm: 0o644,
             nlink: 1,
-            uid: 1000,
-            gid: 1000,
+            uid: self.config.uid,
+            gid: self.config.gid,
             rdev: 0,
             flags: 0,
             blksize: 512,
         };
-        reply.created(&TTL, &attr, 0, 0, 0);
+        Ok((attr, fh))
     }
 
     // 6. WRITE
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite
-    ) {
-        if let Some(buffer) = self.open_files.get_mut(&ino) {
+    fn write(&self, ino: u64, offset: i64, data: &[u8], reply: ReplyWrite) {
+        match self.write_buffer(ino, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Writes into the file's open buffer, growing it (within quota) as needed
+    fn write_buffer(&self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        if let Some(buffer) = self.open_buffer(ino) {
+            let mut buffer = buffer.lock().unwrap();
             let end = (offset as usize) + data.len();
             if end > buffer.data.len() {
+                // Refuse growth past a quota now rather than failing at close
+                self.check_growth(&buffer.filename, end as u64)?;
                 buffer.data.resize(end, 0);
             }
             buffer.data[offset as usize..end].copy_from_slice(data);
-            reply.written(data.len() as u32);
+            buffer.dirty = true;
+            Ok(data.len() as u32)
         } else {
-            reply.error(ENOENT);
+            Err(ENOENT)
         }
     }
 
-    // 7. SETATTR
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr
-    ) {
+    // 7. SETATTR (only the size changes anything for now)
+    fn setattr(&self, ino: u64, size: Option<u64>, reply: ReplyAttr) {
+        match self.set_size(ino, size) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Truncates or extends the file if `size` is given; returns the new attributes
+    fn set_size(&self, ino: u64, size: Option<u64>) -> Result<FileAttr, libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
         if let Some(new_size) = size {
-            if let Some(buffer) = self.open_files.get_mut(&ino) {
+            let Some(filename) = self.path_of(ino) else {
+                return Err(ENOENT);
+            };
+            self.check_growth(&filename, new_size)?;
+
+            if let Some(buffer) = self.open_buffer(ino) {
+                // Open for writing: the buffer is the file until it's committed
+                let mut buffer = buffer.lock().unwrap();
                 buffer.data.resize(new_size as usize, 0);
+                buffer.dirty = true;
+            } else if let Err(e) = self.manager.truncate_file(&filename, new_size) {
+                return Err(errno(&e));
             }
+            self.readers.lock().unwrap().remove(&ino);
         }
-        self.getattr(_req, ino, reply);
+        self.attr_of(ino)
     }
 
-    // 8. RELEASE: Fixed return type (ReplyEmpty)
-    fn release(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty
-    ) {
-        if let Some(buffer) = self.open_files.remove(&ino) {
-            println!("FUSE: Flushing '{}' to Storage...", buffer.filename);
-            let _ = self.manager.write_file(&buffer.filename, &buffer.data);
+    // 7b. FALLOCATE: preallocation and FALLOC_FL_PUNCH_HOLE
+    fn fallocate(&self, ino: u64, offset: u64, length: u64, mode: i32, reply: ReplyEmpty) {
+        match self.allocate_range(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-        reply.ok();
     }
 
-    // 9. UNLINK (Fix: Resolve path from parent)
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name_str = name.to_str().unwrap();
+    /// Preallocates `offset..offset + length`, or punches it out under FALLOC_FL_PUNCH_HOLE
+    fn allocate_range(&self, ino: u64, offset: u64, length: u64, mode: i32) -> Result<(), libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        let Some(filename) = self.path_of(ino) else {
+            return Err(ENOENT);
+        };
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        let punch = mode & libc::FALLOC_FL_PUNCH_HOLE != 0;
+        if mode & !(libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE) != 0 || (punch && !keep_size) {
+            // Collapse/insert/zero ranges, or a punch that isn't KEEP_SIZE (which Linux rejects too)
+            return Err(libc::EOPNOTSUPP);
+        }
+        let end = offset.saturating_add(length);
+        if !punch && !keep_size {
+            self.check_growth(&filename, end)?;
+        }
 
-        // 1. Resolve Parent
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
+        if let Some(buffer) = self.open_buffer(ino) {
+            let mut buffer = buffer.lock().unwrap();
+            let len = buffer.data.len();
+            if punch {
+                buffer.data[(offset as usize).min(len)..(end as usize).min(len)].fill(0);
+            } else if !keep_size && (end as usize) > len {
+                buffer.data.resize(end as usize, 0);
             }
+            buffer.dirty = true;
+        } else {
+            let result = if punch {
+                self.manager.punch_hole(&filename, offset, length)
+            } else {
+                self.manager.allocate(&filename, offset, length, keep_size)
+            };
+            if let Err(e) = result {
+                return Err(errno(&e));
+            }
+        }
+        self.readers.lock().unwrap().remove(&ino);
+        Ok(())
+    }
+
+    // 7c. LSEEK: SEEK_DATA / SEEK_HOLE (the kernel handles the other whences itself)
+    fn lseek(&self, ino: u64, offset: u64, whence: i32, reply: ReplyLseek) {
+        let hole = match whence {
+            libc::SEEK_DATA => false,
+            libc::SEEK_HOLE => true,
+            _ => return reply.error(libc::EINVAL),
+        };
+        let Some(filename) = self.path_of(ino) else {
+            return reply.error(ENOENT);
         };
 
-        // 2. Build Full Path
-        let full_path = if parent_path.is_empty() {
-            name_str.to_string()
+        // An open write buffer is all data: holes only exist once it's stored
+        let found = if let Some(buffer) = self.open_buffer(ino) {
+            let len = buffer.lock().unwrap().data.len() as u64;
+            (offset < len).then_some(if hole { len } else { offset })
         } else {
-            format!("{}/{}", parent_path, name_str)
+            match self.manager.load_recipe(&filename) {
+                Ok(recipe) => recipe.seek(offset, hole),
+                Err(_) => return reply.error(ENOENT),
+            }
+        };
+        match found {
+            Some(position) => reply.offset(position as i64),
+            None => reply.error(libc::ENXIO),
+        }
+    }
+
+    // 7d. FLUSH: close(2) on a handle. POSIX drops the caller's record
+    // locks on any close; under on-close writeback the file is committed
+    // here, so close can report a failure.
+    fn flush(&self, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        match self.flush_handle(ino, fh, lock_owner) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush_handle(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), libc::c_int> {
+        self.release_locks(ino, lock_owner);
+        if self.config.writeback != WritebackPolicy::OnClose || !self.write_handles.lock().unwrap().contains(&fh) {
+            return Ok(());
+        }
+        if let Some(buffer) = self.open_buffer(ino) {
+            // Hold the buffer lock through the commit, so reads and getattrs of
+            // this file wait for it instead of seeing the old version
+            self.commit_buffer(ino, &mut buffer.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    // 7e. FSYNC: commits the file whatever the writeback policy
+    fn fsync(&self, ino: u64, reply: ReplyEmpty) {
+        let result = match self.open_buffer(ino) {
+            Some(buffer) => self.commit_buffer(ino, &mut buffer.lock().unwrap()),
+            // Nothing buffered; make sure earlier metadata changes are on disk
+            None => self.manager.sync().map_err(|_| libc::EIO),
         };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // 7f. FSYNCDIR: directory entries live in the database; flush it
+    fn fsyncdir(&self, reply: ReplyEmpty) {
+        match self.manager.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                eprintln!("FUSE: {}", e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
 
-        // 3. Delete from Backend
-        if let Ok(_) = self.manager.delete_file(&full_path) {
-            let inode = calculate_inode(&full_path);
+    // 7g. COPY_FILE_RANGE: a recipe-level clone, so `cp` within the mount
+    // stores no new data. (FICLONE/FICLONERANGE never get here: the kernel
+    // answers them itself for FUSE, and `cp --reflink=auto` then falls back
+    // to copy_file_range.)
+    fn copy_file_range(&self, ino_in: u64, offset_in: u64, ino_out: u64, offset_out: u64, len: u64, reply: ReplyWrite) {
+        match self.clone_range(ino_in, offset_in, ino_out, offset_out, len) {
+            Ok(copied) => reply.written(copied),
+            Err(e) => reply.error(e),
+        }
+    }
 
-            // 4. Clean up Memory
-            self.open_files.remove(&inode);
-            self.inode_map.remove(&inode);
+    /// Shares the chunks of a range of one file with another; returns the bytes copied
+    fn clone_range(&self, ino_in: u64, offset_in: u64, ino_out: u64, offset_out: u64, len: u64) -> Result<u32, libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        let (Some(src), Some(dst)) = (self.path_of(ino_in), self.path_of(ino_out)) else {
+            return Err(ENOENT);
+        };
+        // The reply can only count up to u32::MAX bytes; the caller loops for the rest
+        let len = len.min(u32::MAX as u64);
+
+        // The copy works on stored recipes, so open files are committed first
+        if let Some(buffer) = self.open_buffer(ino_in)
+            && let Err(e) = self.commit_buffer(ino_in, &mut buffer.lock().unwrap())
+        {
+            return Err(e);
+        }
+        let target = self.open_buffer(ino_out);
+        let mut target = target.as_ref().map(|buffer| buffer.lock().unwrap());
+        if let Some(buffer) = target.as_mut()
+            && let Err(e) = self.commit_buffer(ino_out, buffer)
+        {
+            return Err(e);
+        }
 
-            reply.ok();
+        let copied = match self.manager.copy_range(&src, offset_in, &dst, offset_out, len) {
+            Ok(copied) => copied,
+            Err(e) => return Err(errno(&e)),
+        };
+        if let Some(buffer) = target.as_mut() {
+            // Reload the open copy; it matches storage, so it's clean
+            match self.manager.read_file(&dst) {
+                Ok(data) => buffer.data = data,
+                Err(_) => return Err(libc::EIO),
+            }
+        }
+        self.readers.lock().unwrap().remove(&ino_out);
+        Ok(copied as u32)
+    }
+
+    // 7h. GETLK: F_GETLK, answered from the daemon's lock table
+    fn getlk(&self, ino: u64, lock: Lock, reply: ReplyLock) {
+        let (start, end, typ, pid) = self.lock_query(ino, &lock);
+        reply.locked(start, end, typ, pid);
+    }
+
+    /// The lock in the way of `lock` as (start, end, type, pid), or F_UNLCK
+    fn lock_query(&self, ino: u64, lock: &Lock) -> (u64, u64, i32, u32) {
+        match self.locks.lock().unwrap().conflict(ino, lock) {
+            Some(held) => {
+                let typ = if held.exclusive { libc::F_WRLCK } else { libc::F_RDLCK };
+                (held.start, held.end, typ, held.pid)
+            }
+            None => (lock.start, lock.end, libc::F_UNLCK, 0),
+        }
+    }
+
+    // 7i. SETLK / SETLKW: fcntl record locks, and flock(2) locks (which come
+    // as whole-file locks owned by the open file). A blocked SETLKW parks its
+    // reply in the table rather than holding a worker.
+    fn setlk(&self, ino: u64, lock: Lock, unlock: bool, sleep: bool, reply: ReplyEmpty) {
+        self.set_lock(ino, lock, unlock, sleep, Box::new(move |result| match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }));
+    }
+
+    fn set_lock(&self, ino: u64, lock: Lock, unlock: bool, sleep: bool, answer: LockWaiter) {
+        let mut locks = self.locks.lock().unwrap();
+        let granted = if unlock {
+            locks.unlock(ino, lock.owner, lock.start, lock.end)
         } else {
-            reply.error(ENOENT);
+            match locks.try_lock(ino, lock) {
+                Ok(granted) => granted,
+                Err(_) if !sleep => return answer(Err(libc::EAGAIN)),
+                Err(_) if locks.would_deadlock(ino, &lock) => return answer(Err(libc::EDEADLK)),
+                Err(_) => return locks.wait(ino, lock, answer),
+            }
+        };
+        drop(locks);
+        answer(Ok(()));
+        granted.into_iter().for_each(|waiter| waiter(Ok(())));
+    }
+
+    /// Drops every lock `owner` holds on the file and answers the waiters
+    /// that affects
+    fn release_locks(&self, ino: u64, owner: u64) {
+        let (granted, cancelled) = self.locks.lock().unwrap().release_owner(ino, owner);
+        granted.into_iter().for_each(|waiter| waiter(Ok(())));
+        cancelled.into_iter().for_each(|waiter| waiter(Err(libc::EINTR)));
+    }
+
+    // 8. RELEASE. A flock(2) lock goes away with the last handle on the open
+    // file, which is when this comes with a lock owner.
+    fn release(&self, ino: u64, fh: u64, lock_owner: Option<u64>, reply: ReplyEmpty) {
+        match self.close_handle(ino, fh, lock_owner) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
     }
 
-    // 10. RENAME (Fix: Resolve both paths)
+    /// Drops a handle's locks and its reference on the write buffer,
+    /// committing the buffer when that was the last handle (or under on-close)
+    fn close_handle(&self, ino: u64, fh: u64, lock_owner: Option<u64>) -> Result<(), libc::c_int> {
+        if let Some(owner) = lock_owner {
+            self.release_locks(ino, owner);
+        }
+        if !self.write_handles.lock().unwrap().remove(&fh) {
+            // Read-only handle: drop the cached read state
+            self.readers.lock().unwrap().remove(&ino);
+            return Ok(());
+        }
+
+        let Some(buffer) = self.open_buffer(ino) else {
+            return Ok(());
+        };
+        let mut buffer = buffer.lock().unwrap();
+        buffer.handles -= 1;
+        // Under on-close the flush before this normally committed already
+        let mut result = Ok(());
+        if buffer.handles == 0 || self.config.writeback == WritebackPolicy::OnClose {
+            result = self.commit_buffer(ino, &mut buffer);
+        }
+        if buffer.handles == 0 {
+            if result.is_ok() || self.config.writeback == WritebackPolicy::OnClose {
+                // Under on-close the failed flush already went back to close()
+                self.open_files.write().unwrap().remove(&ino);
+            } else {
+                // Nobody is told about a failed release, so keep the data
+                // dirty for the next periodic flush or the unmount
+                eprintln!("FUSE: Keeping '{}' dirty after its final commit failed", buffer.filename);
+            }
+        }
+        result
+    }
+
+    // 9. UNLINK (Fix: Resolve path from parent)
+    fn unlink(&self, parent: u64, name_str: &str, reply: ReplyEmpty) {
+        match self.remove_file(parent, name_str) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Deletes a file, stored or only buffered so far
+    fn remove_file(&self, parent: u64, name_str: &str) -> Result<(), libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        // 1. Resolve Parent
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
+        };
+
+        // 2. Build Full Path
+        let full_path = child_path(&parent_path, name_str);
+        let inode = calculate_inode(&full_path);
+
+        // 3. Delete from Backend (a file that was created but not flushed yet
+        //    only exists as a buffer)
+        match self.manager.delete_file(&full_path) {
+            Ok(()) => {}
+            Err(WriteError::NotFound) if self.open_buffer(inode).is_some() => {}
+            Err(e) => return Err(errno(&e)),
+        }
+
+        // 4. Clean up Memory
+        self.open_files.write().unwrap().remove(&inode);
+        self.readers.lock().unwrap().remove(&inode);
+        self.inode_map.write().unwrap().remove(&inode);
+        Ok(())
+    }
+
+    // 10. RENAME (Fix: Resolve both paths). `flags` are renameat2's.
     fn rename(
-        &mut self,
-        _req: &Request,
+        &self,
         parent: u64,
-        name: &OsStr,
+        name_str: &str,
         newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
+        new_name_str: &str,
+        flags: u32,
         reply: ReplyEmpty
     ) {
-        let name_str = name.to_str().unwrap();
-        let new_name_str = newname.to_str().unwrap();
+        match self.rename_entry(parent, name_str, newparent, new_name_str, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
 
-        // 1. Resolve Old Path
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
-            }
+    /// Moves a file or directory and everything remembered under it
+    fn rename_entry(&self, parent: u64, name_str: &str, newparent: u64, new_name_str: &str, flags: u32) -> Result<(), libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        let mode = match flags {
+            0 => RenameMode::Replace,
+            libc::RENAME_NOREPLACE => RenameMode::NoReplace,
+            libc::RENAME_EXCHANGE => RenameMode::Exchange,
+            _ => return Err(libc::EINVAL), // RENAME_WHITEOUT, or a combination
         };
-        let old_path = if parent_path.is_empty() {
-            name_str.to_string()
-        } else {
-            format!("{}/{}", parent_path, name_str)
+
+        // 1. Resolve Old Path
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
         };
+        let old_path = child_path(&parent_path, name_str);
 
         // 2. Resolve New Path
-        let new_parent_path = match self.inode_map.get(&newparent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
-            }
+        let Some(new_parent_path) = self.path_of(newparent) else {
+            return Err(ENOENT);
         };
-        let new_path = if new_parent_path.is_empty() {
-            new_name_str.to_string()
+        let new_path = child_path(&new_parent_path, new_name_str);
+
+        // 3. Rename in Backend. A file not flushed yet has nothing stored to
+        //    move; renaming its buffer is enough.
+        let pending = self.open_buffer(calculate_inode(&old_path)).is_some();
+        match self.manager.rename_file(&old_path, &new_path, mode) {
+            Ok(()) => {}
+            Err(WriteError::NotFound) if pending && mode != RenameMode::Exchange => {
+                if mode == RenameMode::NoReplace && self.manager.get_file_metadata(&new_path).is_some() {
+                    return Err(libc::EEXIST);
+                }
+            }
+            Err(e) => return Err(errno(&e)),
+        }
+
+        // 4. Update Maps: whatever was replaced is gone, and everything at or
+        //    under the old name now lives under the new one
+        if mode == RenameMode::Exchange {
+            self.move_paths(&[(&old_path, &new_path), (&new_path, &old_path)]);
         } else {
-            format!("{}/{}", new_parent_path, new_name_str)
-        };
+            let replaced = calculate_inode(&new_path);
+            self.open_files.write().unwrap().remove(&replaced);
+            self.readers.lock().unwrap().remove(&replaced);
+            self.move_paths(&[(&old_path, &new_path)]);
+        }
+        Ok(())
+    }
 
-        // 3. Rename in Backend
-        if let Ok(_) = self.manager.rename_file(&old_path, &new_path) {
-            // 4. Update Maps
-            let old_inode = calculate_inode(&old_path);
-            let new_inode = calculate_inode(&new_path);
+    /// Re-keys every remembered path at or under each `from` to the same
+    /// place under its `to`. All moves apply at once, so an exchange is two.
+    fn move_paths(&self, moves: &[(&str, &str)]) {
+        let relocate = |path: &str| {
+            moves
+                .iter()
+                .find(|(from, _)| quota::prefix_matches(from, path))
+                .map(|(from, to)| format!("{}{}", to, &path[from.len()..]))
+        };
 
-            // If it was open, move the buffer
-            if let Some(mut buffer) = self.open_files.remove(&old_inode) {
-                buffer.filename = new_path.clone();
-                self.open_files.insert(new_inode, buffer);
+        // 1. Inode map
+        let moved: Vec<(u64, String)> = {
+            let mut inode_map = self.inode_map.write().unwrap();
+            let moved: Vec<(u64, String)> = inode_map
+                .iter()
+                .filter_map(|(ino, path)| relocate(path).map(|new_path| (*ino, new_path)))
+                .collect();
+            for (ino, _) in &moved {
+                inode_map.remove(ino);
             }
+            for (_, path) in &moved {
+                inode_map.insert(calculate_inode(path), path.clone());
+            }
+            moved
+        };
 
-            // Update Inode Map
-            self.inode_map.remove(&old_inode);
-            self.inode_map.insert(new_inode, new_path);
+        // 2. Read state is keyed by the old inodes; drop it
+        {
+            let mut readers = self.readers.lock().unwrap();
+            for (ino, _) in &moved {
+                readers.remove(ino);
+            }
+        }
 
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
+        // 3. Open buffers follow their files
+        let mut buffers = Vec::new();
+        {
+            let mut open_files = self.open_files.write().unwrap();
+            let taken: Vec<_> = moved
+                .iter()
+                .filter_map(|(ino, path)| open_files.remove(ino).map(|buffer| (buffer, path)))
+                .collect();
+            for (buffer, path) in taken {
+                open_files.insert(calculate_inode(path), buffer.clone());
+                buffers.push((buffer, path.clone()));
+            }
+        }
+        // Outside the map lock, as in acquire_buffer
+        for (buffer, path) in buffers {
+            buffer.lock().unwrap().filename = path;
         }
     }
 
     // 11. OPEN (Optimized with Inode Map)
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&self, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.open_handle(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// A new handle; opening for writing loads the file into a shared buffer
+    fn open_handle(&self, ino: u64, flags: i32) -> Result<u64, libc::c_int> {
+        let fh = self.new_handle();
         let is_read_only = (flags & libc::O_ACCMODE) == libc::O_RDONLY;
         if is_read_only {
-            reply.opened(0, 0);
-            return;
+            return Ok(fh);
         }
-
-        if self.open_files.contains_key(&ino) {
-            reply.opened(0, 0);
-            return;
+        if self.config.read_only {
+            return Err(libc::EROFS);
         }
 
         // Use Map instead of listing all files
-        if let Some(filename) = self.inode_map.get(&ino) {
-            if let Ok(data) = self.manager.read_file(filename) {
-                let buffer = WriteBuffer {
-                    filename: filename.clone(),
-                    data: data,
-                };
-                self.open_files.insert(ino, buffer);
-                reply.opened(0, 0);
-                return;
-            }
+        let Some(filename) = self.path_of(ino) else {
+            return Err(ENOENT);
+        };
+        // A file whose chunks can't be read can't be written either; saying
+        // so here beats failing every write on the handle
+        if let Err(e) = self.acquire_buffer(ino, &filename) {
+            eprintln!("FUSE: Cannot open '{}' for writing: {}", filename, e);
+            return Err(libc::EIO);
         }
-        reply.opened(0, 0);
+        self.write_handles.lock().unwrap().insert(fh);
+        Ok(fh)
     }
 
     // 12. MKDIR (Supports Nesting)
-    fn mkdir(
+    fn mkdir(&self, parent: u64, name_str: &str, reply: ReplyEntry) {
+        match self.make_directory(parent, name_str) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Creates `name_str` in directory `parent`; EEXIST if the name is taken
+    fn make_directory(&self, parent: u64, name_str: &str) -> Result<FileAttr, libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        // 1. Resolve Parent
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
+        };
+
+        // 2. Build Full Path
+        let full_path = child_path(&parent_path, name_str);
+
+        // 3. Create (EEXIST if the name is taken)
+        if let Err(e) = self.manager.create_directory(&full_path) {
+            return Err(errno(&e));
+        }
+        let inode = calculate_inode(&full_path);
+
+        // 4. Update Map
+        self.inode_map.write().unwrap().insert(inode, full_path);
+
+        let attr = FileAttr {
+            ino: inode,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: self.config.uid,
+            gid: self.config.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        };
+        Ok(attr)
+    }
+
+    // 13. RMDIR
+    fn rmdir(&self, parent: u64, name_str: &str, reply: ReplyEmpty) {
+        match self.remove_directory(parent, name_str) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Removes an empty directory
+    fn remove_directory(&self, parent: u64, name_str: &str) -> Result<(), libc::c_int> {
+        if self.config.read_only {
+            return Err(libc::EROFS);
+        }
+        // 1. Resolve Parent Path
+        let Some(parent_path) = self.path_of(parent) else {
+            return Err(ENOENT);
+        };
+
+        // 2. Build Full Path
+        let full_path = child_path(&parent_path, name_str);
+
+        // 3. Remove from Database: ENOTEMPTY while it has children,
+        //    ENOTDIR if it's a file
+        if let Err(e) = self.manager.remove_directory(&full_path) {
+            return Err(errno(&e));
+        }
+
+        // 4. Clean up Memory
+        self.inode_map.write().unwrap().remove(&calculate_inode(&full_path));
+        Ok(())
+    }
+
+    // 14. GETXATTR (Only the cache statistics on the root for now)
+    fn getxattr(&self, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if ino != 1 || name != CACHE_STATS_XATTR {
+            return reply.error(libc::ENODATA);
+        }
+        let stats = self.manager.cache_stats();
+        let value = format!(
+            "hits={} misses={} hit_rate={:.3} entries={} bytes={} capacity={}",
+            stats.hits,
+            stats.misses,
+            stats.hit_rate(),
+            stats.entries,
+            stats.bytes,
+            stats.capacity
+        );
+        reply_xattr(reply, size, value.as_bytes());
+    }
+
+    // 15. LISTXATTR
+    fn listxattr(&self, ino: u64, size: u32, reply: ReplyXattr) {
+        let names = if ino == 1 { format!("{}\0", CACHE_STATS_XATTR) } else { String::new() };
+        reply_xattr(reply, size, names.as_bytes());
+    }
+
+    // 16. STATFS: `df` shows the space the chunks really take up
+    fn statfs(&self, reply: ReplyStatfs) {
+        let Ok(space) = self.manager.space_report() else {
+            return reply.error(libc::EIO);
+        };
+        let blocks = |bytes: u64| bytes / (STATFS_BLOCK_SIZE as u64);
+        let free = blocks(space.available_bytes);
+        reply.statfs(
+            blocks(space.total_bytes),
+            free,
+            free,
+            space.files,
+            space.free_inodes,
+            STATFS_BLOCK_SIZE,
+            MAX_NAME_LENGTH,
+            STATFS_BLOCK_SIZE
+        );
+    }
+}
+
+// Each callback copies what it needs out of the borrowed arguments and
+// queues the real work. Replies are sent from whichever worker runs it.
+impl Filesystem for BetterFS {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        // Ask for READDIRPLUS so `ls -l` gets attributes without a LOOKUP per
+        // entry; older kernels just keep sending READDIR
+        let _ = config.add_capabilities(FUSE_DO_READDIRPLUS);
+        // Have the kernel send fcntl and flock locks here instead of keeping
+        // them local to this machine's view of the mount
+        let _ = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS);
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.lookup(parent, &name, reply));
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        self.dispatch(move |fs| fs.getattr(ino, reply));
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        self.dispatch(move |fs| fs.readlink(ino, reply));
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        reply: ReplyDirectory
+    ) {
+        self.dispatch(move |fs| fs.readdir(ino, offset, reply));
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        reply: ReplyDirectoryPlus
+    ) {
+        self.dispatch(move |fs| fs.readdirplus(ino, offset, reply));
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData
+    ) {
+        self.dispatch(move |fs| fs.read(ino, offset, size, reply));
+    }
+
+    fn create(
         &mut self,
         _req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
         _umask: u32,
-        reply: ReplyEntry
+        flags: i32,
+        reply: ReplyCreate
     ) {
-        let name_str = name.to_str().unwrap();
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.create(parent, &name, flags, reply));
+    }
 
-        // 1. Resolve Parent
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
-            }
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite
+    ) {
+        let data = data.to_vec();
+        self.dispatch_for_handle(fh, move |fs| fs.write(ino, offset, &data, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr
+    ) {
+        match fh {
+            Some(fh) => self.dispatch_for_handle(fh, move |fs| fs.setattr(ino, size, reply)),
+            None => self.dispatch(move |fs| fs.setattr(ino, size, reply)),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty
+    ) {
+        if offset < 0 || length <= 0 {
+            return reply.error(libc::EINVAL);
+        }
+        self.dispatch_for_handle(fh, move |fs| fs.fallocate(ino, offset as u64, length as u64, mode, reply));
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek
+    ) {
+        if offset < 0 {
+            return reply.error(libc::ENXIO);
+        }
+        self.dispatch(move |fs| fs.lseek(ino, offset as u64, whence, reply));
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.dispatch_for_handle(fh, move |fs| fs.flush(ino, fh, lock_owner, reply));
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock
+    ) {
+        let lock = match requested_lock(lock_owner, pid, start, end, typ) {
+            Ok(lock) => lock,
+            Err(e) => return reply.error(e),
         };
+        self.dispatch(move |fs| fs.getlk(ino, lock, reply));
+    }
 
-        // 2. Build Full Path
-        let full_path = if parent_path.is_empty() {
-            name_str.to_string()
-        } else {
-            format!("{}/{}", parent_path, name_str)
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty
+    ) {
+        let lock = match requested_lock(lock_owner, pid, start, end, typ) {
+            Ok(lock) => lock,
+            Err(e) => return reply.error(e),
         };
+        let unlock = typ == libc::F_UNLCK;
+        self.dispatch_for_handle(fh, move |fs| fs.setlk(ino, lock, unlock, sleep, reply));
+    }
 
-        // 3. Create
-        if let Ok(_) = self.manager.create_directory(&full_path) {
-            let inode = calculate_inode(&full_path);
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.dispatch_for_handle(fh, move |fs| fs.fsync(ino, reply));
+    }
 
-            // 4. Update Map
-            self.inode_map.insert(inode, full_path);
+    fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.dispatch(move |fs| fs.fsyncdir(reply));
+    }
 
-            let attr = FileAttr {
-                ino: inode,
-                size: 0,
-                blocks: 0,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 2,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-            reply.entry(&TTL, &attr, 0);
-        } else {
-            reply.error(ENOENT);
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite
+    ) {
+        if offset_in < 0 || offset_out < 0 || flags != 0 {
+            return reply.error(libc::EINVAL);
         }
+        self.dispatch_for_handle(fh_out, move |fs| {
+            fs.copy_file_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len, reply)
+        });
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty
+    ) {
+        self.dispatch_for_handle(fh, move |fs| fs.release(ino, fh, lock_owner, reply));
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.unlink(parent, &name, reply));
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty
+    ) {
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        let newname = match utf8_name(newname) {
+            Ok(newname) => newname,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.rename(parent, &name, newparent, &newname, flags, reply));
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.dispatch(move |fs| fs.open(ino, flags, reply));
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry
+    ) {
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.mkdir(parent, &name, reply));
     }
 
-    // 13. RMDIR
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name_str = name.to_str().unwrap();
+        let name = match utf8_name(name) {
+            Ok(name) => name,
+            Err(e) => return reply.error(e),
+        };
+        self.dispatch(move |fs| fs.rmdir(parent, &name, reply));
+    }
 
-        // 1. Resolve Parent Path
-        let parent_path = match self.inode_map.get(&parent) {
-            Some(p) => p,
-            None => {
-                return reply.error(ENOENT);
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_os_string();
+        self.dispatch(move |fs| fs.getxattr(ino, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.dispatch(move |fs| fs.listxattr(ino, size, reply));
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.dispatch(move |fs| fs.statfs(reply));
+    }
+
+    // DESTROY (Unmount): Leave a summary of how well the cache did.
+    // Dropping BetterFS afterwards lets queued requests finish first.
+    fn destroy(&mut self) {
+        // Last chance for buffers whose final commit failed
+        self.state.commit_dirty();
+        let stats = self.state.manager.cache_stats();
+        println!(
+            "FUSE: Chunk cache {} hits / {} misses ({:.1}% hit rate)",
+            stats.hits,
+            stats.misses,
+            stats.hit_rate() * 100.0
+        );
+    }
+}
+
+// Periodic writeback runs on its own thread. It stops when the sender is
+// dropped, or when the filesystem itself is gone.
+fn spawn_writeback(state: Weak<FsState>, interval: Duration) -> mpsc::Sender<()> {
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    std::thread::Builder::new()
+        .name("writeback".to_string())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let Some(state) = state.upgrade() else {
+                    break;
+                };
+                state.commit_dirty();
             }
-        };
+        })
+        .expect("Failed to spawn writeback thread");
+    stop_tx
+}
 
-        // 2. Build Full Path
-        let full_path = if parent_path.is_empty() {
-            name_str.to_string()
-        } else {
-            format!("{}/{}", parent_path, name_str)
+// HELPER: The lock a GETLK or SETLK is about. The kernel's lock owner
+// identifies the process's locks (or, for flock, the open file); the pid is
+// only reported back by F_GETLK.
+fn requested_lock(lock_owner: u64, pid: u32, start: u64, end: u64, typ: i32) -> Result<Lock, libc::c_int> {
+    if ![libc::F_RDLCK, libc::F_WRLCK, libc::F_UNLCK].contains(&typ) || start > end {
+        return Err(libc::EINVAL);
+    }
+    Ok(Lock { owner: lock_owner, pid, start, end, exclusive: typ == libc::F_WRLCK })
+}
+
+// HELPER: The errno for a refused metadata change
+fn errno(error: &WriteError) -> libc::c_int {
+    match error {
+        WriteError::Quota(_) => libc::EDQUOT,
+        WriteError::NotFound => ENOENT,
+        WriteError::Exists => libc::EEXIST,
+        WriteError::NotEmpty => libc::ENOTEMPTY,
+        WriteError::IsDirectory => libc::EISDIR,
+        WriteError::NotDirectory => libc::ENOTDIR,
+        WriteError::Invalid => libc::EINVAL,
+        WriteError::Failed(_) => libc::EIO,
+    }
+}
+
+// xattr replies: size 0 asks for the length, a too-small buffer is ERANGE
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if (size as usize) < value.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ noise, reset };
+    use std::fs;
+
+    #[test]
+    fn test_readdir_cookies_resume_across_changes() {
+        let db_path = "./test_fuse_readdir";
+        reset(db_path);
+
+        let manager = FileManager::new(db_path);
+        manager.create_directory("big").unwrap();
+        for i in 0..200 {
+            manager.write_file(&format!("big/f{:03}", i), b"x").unwrap();
+        }
+        manager.create_directory("big/nested").unwrap();
+        manager.write_file("big/nested/deep.txt", b"y").unwrap();
+        let fs_impl = BetterFS::new(manager, FsConfig::default());
+        let state = &fs_impl.state;
+        let dir = calculate_inode("big");
+
+        // 1. Full listing: dot entries first, direct children only, cookies ascending
+        let all = state.directory_page(dir, 0).unwrap();
+        assert_eq!((all[0].name.as_str(), all[0].ino), (".", dir));
+        assert_eq!((all[1].name.as_str(), all[1].ino), ("..", 1));
+        assert_eq!(all.len(), 2 + 201);
+        assert!(all.windows(2).all(|pair| pair[0].cookie < pair[1].cookie));
+        assert!(!all.iter().any(|entry| entry.name == "deep.txt"));
+
+        // 2. The buffer "fills" after 100 entries; the directory changes
+        //    before the kernel comes back for the rest
+        let last_seen = all[99].cookie;
+        let removed = all[150].name.clone();
+        state.manager.delete_file(&format!("big/{}", removed)).unwrap();
+        state.manager.write_file("big/added", b"z").unwrap();
+        let rest = state.directory_page(dir, last_seen).unwrap();
+
+        // Nothing is repeated, and every surviving entry is still delivered
+        let first: HashSet<&str> = all[..100].iter().map(|e| e.name.as_str()).collect();
+        assert!(rest.iter().all(|entry| !first.contains(entry.name.as_str())));
+        for entry in &all[100..] {
+            let present = rest.iter().any(|e| e.name == entry.name);
+            assert_eq!(present, entry.name != removed, "{}", entry.name);
+        }
+
+        // A new entry shows up only if it sorts after the resume point
+        let added = rest.iter().any(|e| e.name == "added");
+        assert_eq!(added, dir_cookie("added") > last_seen);
+
+        // 3. Past the last cookie there is nothing left
+        assert!(state.directory_page(dir, rest.last().unwrap().cookie).unwrap().is_empty());
+
+        drop(fs_impl);
+        fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_mount_refuses_changes() {
+        let db_path = "./test_fuse_read_only";
+        reset(db_path);
+
+        let manager = FileManager::new(db_path);
+        manager.create_directory("docs").unwrap();
+        manager.write_file("docs/a.txt", b"hello").unwrap();
+        let config = FsConfig { read_only: true, ..FsConfig::default() };
+        let fs_impl = BetterFS::new(manager, config);
+        let state = &fs_impl.state;
+        let docs = state.lookup_entry(1, "docs").unwrap().ino;
+        let file = state.lookup_entry(docs, "a.txt").unwrap().ino;
+
+        // 1. Every change is refused up front
+        assert_eq!(state.create_file(docs, "new.txt", 0).err(), Some(libc::EROFS));
+        assert_eq!(state.open_handle(file, libc::O_WRONLY).err(), Some(libc::EROFS));
+        assert_eq!(state.open_handle(file, libc::O_RDWR).err(), Some(libc::EROFS));
+        assert_eq!(state.write_buffer(file, 0, b"x").err(), Some(libc::EROFS));
+        assert_eq!(state.set_size(file, Some(0)).err(), Some(libc::EROFS));
+        assert_eq!(state.allocate_range(file, 0, 4096, 0).err(), Some(libc::EROFS));
+        assert_eq!(state.clone_range(file, 0, file, 5, 5).err(), Some(libc::EROFS));
+        assert_eq!(state.remove_file(docs, "a.txt").err(), Some(libc::EROFS));
+        assert_eq!(state.rename_entry(docs, "a.txt", 1, "b.txt", 0).err(), Some(libc::EROFS));
+        assert_eq!(state.make_directory(1, "more").err(), Some(libc::EROFS));
+        assert_eq!(state.remove_directory(1, "docs").err(), Some(libc::EROFS));
+        assert!(state.open_buffer(file).is_none());
+        for absent in ["docs/new.txt", "b.txt", "more"] {
+            assert!(state.manager.get_file_metadata(absent).is_none(), "{}", absent);
+        }
+
+        // 2. Reads still work
+        let fh = state.open_handle(file, libc::O_RDONLY).unwrap();
+        assert_eq!(state.read_range(file, 0, 4096).unwrap(), b"hello");
+        assert_eq!(state.attr_of(file).unwrap().size, 5);
+        let names: Vec<String> = state.directory_page(docs, 0).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec![".", "..", "a.txt"]);
+        state.close_handle(file, fh, None).unwrap();
+
+        drop(fs_impl);
+        fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_requests_through_the_handler() {
+        let db_path = "./test_fuse_locks";
+        reset(db_path);
+
+        let manager = FileManager::new(db_path);
+        manager.write_file("shared.db", b"rows").unwrap();
+        let fs_impl = BetterFS::new(manager, FsConfig::default());
+        let state = &fs_impl.state;
+        let ino = state.lookup_entry(1, "shared.db").unwrap().ino;
+        let (answers_tx, answers) = mpsc::channel();
+        let answer = |tag: &'static str| -> LockWaiter {
+            let tx = answers_tx.clone();
+            Box::new(move |result| tx.send((tag, result)).unwrap())
         };
 
-        // 3. Remove from Database
-        // Note: Real filesystems check if the directory is empty first.
-        // We are skipping that check for simplicity (allowing "force" delete).
-        if let Ok(_) = self.manager.delete_file(&full_path) {
-            let inode = calculate_inode(&full_path);
+        // 1. Owner and pid come through as given; bad types and ranges are EINVAL
+        let write = requested_lock(7, 700, 0, 99, libc::F_WRLCK).unwrap();
+        assert_eq!(write, Lock { owner: 7, pid: 700, start: 0, end: 99, exclusive: true });
+        assert!(!requested_lock(7, 700, 0, 99, libc::F_RDLCK).unwrap().exclusive);
+        assert_eq!(requested_lock(7, 700, 0, 99, 42), Err(libc::EINVAL));
+        assert_eq!(requested_lock(7, 700, 100, 99, libc::F_RDLCK), Err(libc::EINVAL));
+
+        // 2. F_GETLK reports the holder's range, type and pid to other owners only
+        state.set_lock(ino, write, false, false, answer("a"));
+        assert_eq!(answers.try_recv(), Ok(("a", Ok(()))));
+        let probe = requested_lock(8, 800, 50, 60, libc::F_RDLCK).unwrap();
+        assert_eq!(state.lock_query(ino, &probe), (0, 99, libc::F_WRLCK, 700));
+        assert_eq!(state.lock_query(ino, &write).2, libc::F_UNLCK);
+
+        // 3. A conflicting F_SETLK is EAGAIN; F_SETLKW waits until the holder
+        // closes any handle on the file (flush)
+        state.set_lock(ino, probe, false, false, answer("b"));
+        assert_eq!(answers.try_recv(), Ok(("b", Err(libc::EAGAIN))));
+        state.set_lock(ino, probe, false, true, answer("b"));
+        assert!(answers.try_recv().is_err());
+        state.flush_handle(ino, 0, 7).unwrap();
+        assert_eq!(answers.try_recv(), Ok(("b", Ok(()))));
+
+        // 4. flock: a whole-file lock owned by the open file, gone with its
+        // last release; a waiter of the closing owner is cancelled
+        let flock = requested_lock(9, 900, 0, u64::MAX, libc::F_WRLCK).unwrap();
+        state.set_lock(ino, flock, false, true, answer("c"));
+        assert!(answers.try_recv().is_err());
+        state.close_handle(ino, 1, Some(9)).unwrap();
+        assert_eq!(answers.try_recv(), Ok(("c", Err(libc::EINTR))));
+        state.close_handle(ino, 2, Some(8)).unwrap();
+        state.set_lock(ino, flock, false, false, answer("c"));
+        assert_eq!(answers.try_recv(), Ok(("c", Ok(()))));
+        assert_eq!(state.lock_query(ino, &probe), (0, u64::MAX, libc::F_WRLCK, 900));
+
+        drop(fs_impl);
+        fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_writeback_commits_dirty_buffers() {
+        let db_path = "./test_fuse_writeback";
+        reset(db_path);
+
+        // 1. Policy names, as the mount option takes them
+        assert_eq!("on-fsync".parse::<WritebackPolicy>(), Ok(WritebackPolicy::OnFsync));
+        let periodic = WritebackPolicy::Periodic(Duration::from_secs(30));
+        assert_eq!("periodic:30".parse::<WritebackPolicy>(), Ok(periodic));
+        assert_eq!(periodic.to_string(), "periodic:30");
+        assert!("periodic:0".parse::<WritebackPolicy>().is_err());
+        assert!("sometimes".parse::<WritebackPolicy>().is_err());
+
+        let mut manager = FileManager::new(db_path);
+        manager.write_file("log.txt", b"old").unwrap();
+        // "full/" starts out over its quota
+        manager.create_directory("full").unwrap();
+        manager.write_file("full/filler.bin", &noise(256 * 1024, 2)).unwrap();
+        manager.write_file("full/out.bin", b"").unwrap();
+        manager.set_quota_limits("full", Some(quota::QuotaLimits { physical: Some(128 * 1024), ..quota::QuotaLimits::default() })).unwrap();
+        // The thread never ticks during the test; it calls `commit_dirty` itself
+        let config = FsConfig { writeback: WritebackPolicy::Periodic(Duration::from_secs(3600)), ..FsConfig::default() };
+        let fs_impl = BetterFS::new(manager, config);
+        let state = &fs_impl.state;
+        let ino = calculate_inode("log.txt");
+
+        // 2. A clean buffer commits nothing; a dirty one becomes the recipe
+        state.acquire_buffer(ino, "log.txt").unwrap();
+        let buffer = state.open_buffer(ino).unwrap();
+        state.commit_buffer(ino, &mut buffer.lock().unwrap()).unwrap();
+        {
+            let mut buffer = buffer.lock().unwrap();
+            buffer.data = b"fsynced".to_vec();
+            buffer.dirty = true;
+        }
+        state.commit_buffer(ino, &mut buffer.lock().unwrap()).unwrap();
+        assert!(!buffer.lock().unwrap().dirty);
+        assert_eq!(state.manager.read_file("log.txt").unwrap(), b"fsynced");
+
+        // 3. The periodic tick picks up what's dirty without any close or fsync
+        {
+            let mut buffer = buffer.lock().unwrap();
+            buffer.data.extend_from_slice(b", then appended");
+            buffer.dirty = true;
+        }
+        state.commit_dirty();
+        assert!(!buffer.lock().unwrap().dirty);
+        assert_eq!(state.manager.read_file("log.txt").unwrap(), b"fsynced, then appended");
+
+        // 4. A final commit that fails keeps the buffer, and a later tick stores it
+        let out = calculate_inode("full/out.bin");
+        let fh = 7;
+        state.acquire_buffer(out, "full/out.bin").unwrap();
+        state.write_handles.lock().unwrap().insert(fh);
+        {
+            let buffer = state.open_buffer(out).unwrap();
+            let mut buffer = buffer.lock().unwrap();
+            buffer.data = noise(4096, 1);
+            buffer.dirty = true;
+        }
+        assert_eq!(state.close_handle(out, fh, None), Err(libc::EDQUOT));
+        state.commit_dirty();
+        assert!(state.open_buffer(out).unwrap().lock().unwrap().dirty);
+
+        state.manager.delete_file("full/filler.bin").unwrap();
+        state.commit_dirty();
+        assert!(state.open_buffer(out).is_none());
+        assert_eq!(state.manager.read_file("full/out.bin").unwrap(), noise(4096, 1));
+
+        drop(buffer);
+        drop(fs_impl);
+        fs::remove_dir_all(db_path).unwrap();
+    }
 
-            // 4. Clean up Memory
-            self.inode_map.remove(&inode);
+    #[test]
+    fn test_quota_checks_follow_usage() {
+        let db_path = "./test_fuse_quota_cache";
+        reset(db_path);
+
+        let mut manager = FileManager::new(db_path);
+        manager.create_directory("team").unwrap();
+        manager.write_file("team/a.bin", b"").unwrap();
+        manager.set_quota_limits("team", Some(quota::QuotaLimits { logical: Some(10_000), ..quota::QuotaLimits::default() })).unwrap();
+        let fs_impl = BetterFS::new(manager, FsConfig::default());
+        let state = &fs_impl.state;
+        let ino = calculate_inode("team/a.bin");
+
+        // 1. Growing writes are checked against the quota before anything is committed
+        state.acquire_buffer(ino, "team/a.bin").unwrap();
+        assert_eq!(state.write_buffer(ino, 0, &[1u8; 8000]), Ok(8000));
+        assert_eq!(state.write_buffer(ino, 8000, &[1u8; 4000]), Err(libc::EDQUOT));
+
+        // 2. Usage committed elsewhere is seen by the next check
+        state.manager.write_file("team/b.bin", &[2u8; 6000]).unwrap();
+        assert_eq!(state.check_growth("team/a.bin", 8000), Err(libc::EDQUOT));
+        assert_eq!(state.set_size(ino, Some(9000)), Err(libc::EDQUOT));
+        state.manager.delete_file("team/b.bin").unwrap();
+        assert_eq!(state.write_buffer(ino, 8000, &[1u8; 2000]), Ok(2000));
+
+        // 3. Paths outside any quota are never limited
+        assert_eq!(state.check_growth("elsewhere.bin", u64::MAX), Ok(()));
+
+        drop(fs_impl);
+        fs::remove_dir_all(db_path).unwrap();
+    }
 
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
+    #[test]
+    fn test_requests_on_a_handle_run_in_order() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let db_path = "./test_fuse_handle_order";
+        reset(db_path);
+        let fs_impl = BetterFS::new(FileManager::new(db_path), FsConfig::default());
+
+        // 1. A slow request holds back the ones behind it on its handle; every
+        //    queued request still runs before the pool shuts down
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (fh, step, delay) in [(1, "write", 50), (2, "other", 0), (1, "release", 0)] {
+            let order = order.clone();
+            fs_impl.dispatch_for_handle(fh, move |_| {
+                std::thread::sleep(Duration::from_millis(delay));
+                order.lock().unwrap().push(step);
+            });
         }
+        drop(fs_impl);
+        let order = order.lock().unwrap();
+        let position = |step| order.iter().position(|&s| s == step).unwrap();
+        assert!(position("write") < position("release"));
+        assert_eq!(order.len(), 3);
+
+        // 2. Names that aren't UTF-8 are refused, not mangled
+        assert_eq!(utf8_name(OsStr::new("notes.txt")), Ok("notes.txt".to_string()));
+        assert_eq!(utf8_name(OsStr::from_bytes(b"caf\xe9.txt")), Err(libc::EINVAL));
+
+        fs::remove_dir_all(db_path).unwrap();
     }
 }
@@ -0,0 +1,362 @@
+// src/import.rs
+use crate::file_manager::{ FileManager, WriteError };
+use crate::quota::normalize_prefix;
+use crate::recipe::{ FileKind, FileMeta };
+use crate::repo::MARKER_FILE;
+use ignore::WalkBuilder;
+use ignore::overrides::OverrideBuilder;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{ Path, PathBuf };
+
+/// How `import_tree` picks and stores entries (`better-fs import`)
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Repository directory the tree lands in ("" is the root)
+    pub prefix: String,
+    /// Store what symlinks point to instead of the links themselves
+    pub follow_symlinks: bool,
+    /// Only files matching one of these globs (gitignore syntax); empty means all
+    pub include: Vec<String>,
+    /// Skip matching files and directories, even if included
+    pub exclude: Vec<String>,
+    /// Honour .gitignore, .git/info/exclude and the global gitignore, and skip .git
+    pub gitignore: bool,
+}
+
+/// Outcome of `import_tree`
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Content bytes of the files imported
+    pub bytes: u64,
+    /// "path: reason" for every entry that was left out
+    pub skipped: Vec<String>,
+}
+
+/// A file, directory or symlink found under the import source
+#[derive(Debug, Clone)]
+pub struct HostEntry {
+    pub path: PathBuf,
+    /// Where it goes in the repository
+    pub dest: String,
+    pub kind: FileKind,
+    /// Content length (a symlink's is the length of its target)
+    pub size: u64,
+    pub meta: FileMeta,
+}
+
+impl HostEntry {
+    /// The content to store: the file's bytes, or the link's target
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self.kind {
+            FileKind::Symlink => Ok(fs::read_link(&self.path)?.into_os_string().into_vec()),
+            _ => fs::read(&self.path),
+        }
+    }
+}
+
+/// Walks `source` with the filters of `options`, parents before children.
+/// Also returns "path: reason" for every entry that can't be imported
+/// (unreadable, not UTF-8, or a socket, device, ...).
+pub fn scan(source: &Path, options: &ImportOptions) -> Result<(Vec<HostEntry>, Vec<String>), String> {
+    let prefix = normalize_prefix(&options.prefix);
+
+    // Includes first, so that an exclude matching the same path wins
+    let mut overrides = OverrideBuilder::new(source);
+    for glob in &options.include {
+        overrides.add(glob).map_err(|e| format!("Bad include pattern '{}': {}", glob, e))?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{}", glob)).map_err(|e| format!("Bad exclude pattern '{}': {}", glob, e))?;
+    }
+    let overrides = overrides.build().map_err(|e| e.to_string())?;
+
+    let gitignore = options.gitignore;
+    let walk = WalkBuilder::new(source)
+        .standard_filters(false)
+        .git_ignore(gitignore)
+        .git_exclude(gitignore)
+        .git_global(gitignore)
+        .require_git(false)
+        .follow_links(options.follow_symlinks)
+        .overrides(overrides)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        // Never copy a repository into itself (or any other one)
+        .filter_entry(move |entry| {
+            let git_dir = gitignore && entry.file_name() == ".git";
+            !git_dir && !entry.path().join(MARKER_FILE).exists()
+        })
+        .build();
+
+    let (mut entries, mut skipped) = (Vec::new(), Vec::new());
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                skipped.push(e.to_string());
+                continue;
+            }
+        };
+        let path = entry.path();
+        let Some(dest) = destination(&prefix, source, path) else {
+            skipped.push(format!("{}: name is not valid UTF-8", path.display()));
+            continue;
+        };
+        let (Some(file_type), Ok(metadata)) = (entry.file_type(), entry.metadata()) else {
+            skipped.push(format!("{}: could not read its metadata", path.display()));
+            continue;
+        };
+        let kind = if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            skipped.push(format!("{}: not a file, directory or symlink", path.display()));
+            continue;
+        };
+        if dest.is_empty() {
+            continue; // The source directory imported at the root: nothing to store
+        }
+        let size = if kind == FileKind::Directory { 0 } else { metadata.len() };
+        let meta = FileMeta::from_fs(&metadata);
+        entries.push(HostEntry { path: path.to_path_buf(), dest, kind, size, meta });
+    }
+    Ok((entries, skipped))
+}
+
+/// Copies the tree at `source` into the repository under `options.prefix`,
+/// keeping relative paths, permission bits and mtimes. Entries that can't be
+/// read (or are sockets, devices, ...) are skipped and reported; a failed
+/// store, e.g. over quota, stops the import.
+pub fn import_tree(manager: &FileManager, source: &Path, options: &ImportOptions) -> Result<ImportReport, String> {
+    make_prefix(manager, &options.prefix)?;
+    let (entries, skipped) = scan(source, options)?;
+    let mut report = ImportReport { skipped, ..ImportReport::default() };
+
+    // Directories are made when something lands in them, so that a filter
+    // matching nothing inside doesn't leave empty ones behind
+    let mut pending: BTreeMap<String, FileMeta> = BTreeMap::new();
+    for entry in entries {
+        if entry.kind == FileKind::Directory {
+            pending.insert(entry.dest, entry.meta);
+            continue;
+        }
+        let data = match entry.read() {
+            Ok(data) => data,
+            Err(e) => {
+                report.skipped.push(format!("{}: {}", entry.path.display(), e));
+                continue;
+            }
+        };
+
+        if let Err(e) = make_parents(manager, &mut pending, &entry.dest, &mut report) {
+            skip_or_fail(e, &entry.path, &mut report.skipped)?;
+            continue;
+        }
+        if let Some((_, FileKind::Directory)) = manager.get_file_metadata(&entry.dest) {
+            report.skipped.push(format!("{}: a directory is in the way", entry.path.display()));
+            continue;
+        }
+        match manager.write_file_with_meta(&entry.dest, &data, entry.kind.clone(), entry.meta) {
+            Ok(()) if entry.kind == FileKind::Symlink => report.symlinks += 1,
+            Ok(()) => {
+                report.files += 1;
+                report.bytes += data.len() as u64;
+            }
+            Err(e) => skip_or_fail(e, &entry.path, &mut report.skipped)?,
+        }
+    }
+
+    // Without include patterns the tree is copied whole, empty directories too
+    if options.include.is_empty() {
+        while let Some((dir, meta)) = pending.pop_first() {
+            let made = make_parents(manager, &mut pending, &dir, &mut report)
+                .and_then(|()| manager.ensure_directory(&dir, meta));
+            match made {
+                Ok(()) => report.directories += 1,
+                Err(e) => skip_or_fail(e, Path::new(&dir), &mut report.skipped)?,
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// The directories of `prefix` that don't exist yet, as mkdir -p would make
+/// them. Like mkdir -p, fails if something on the way is a file.
+pub fn make_prefix(manager: &FileManager, prefix: &str) -> Result<(), String> {
+    let mut ancestor = String::new();
+    for part in normalize_prefix(prefix).split('/').filter(|part| !part.is_empty()) {
+        ancestor = join(&ancestor, part);
+        let made = match manager.get_file_metadata(&ancestor) {
+            None => manager.ensure_directory(&ancestor, FileMeta::now()),
+            Some((_, FileKind::Directory)) => Ok(()),
+            Some(_) => Err(WriteError::NotDirectory),
+        };
+        made.map_err(|e| format!("{}: {}", ancestor, e))?;
+    }
+    Ok(())
+}
+
+// Repository path for `path` under `source`: its relative path below `prefix`
+// (a lone file keeps its name). None if it isn't valid UTF-8.
+fn destination(prefix: &str, source: &Path, path: &Path) -> Option<String> {
+    let relative = match path.strip_prefix(source) {
+        Ok(rest) if rest.as_os_str().is_empty() && !path.is_dir() => Path::new(path.file_name()?),
+        Ok(rest) => rest,
+        Err(_) => return None,
+    };
+    let mut dest = prefix.to_string();
+    for part in relative.components() {
+        dest = join(&dest, part.as_os_str().to_str()?);
+    }
+    Some(dest)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) }
+}
+
+// Creates the not yet created directories above `dest`, outermost first.
+// `NotDirectory` if one of them is already a file: `dest` would be orphaned.
+fn make_parents(
+    manager: &FileManager,
+    pending: &mut BTreeMap<String, FileMeta>,
+    dest: &str,
+    report: &mut ImportReport
+) -> Result<(), WriteError> {
+    for dir in dest.match_indices('/').map(|(i, _)| &dest[..i]) {
+        match pending.remove(dir) {
+            Some(meta) => {
+                manager.ensure_directory(dir, meta)?;
+                report.directories += 1;
+            }
+            None => {
+                if let Some((_, FileKind::File | FileKind::Symlink)) = manager.get_file_metadata(dir) {
+                    return Err(WriteError::NotDirectory);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name clashes cost one entry; anything else (quota, storage) ends the import
+pub fn skip_or_fail(error: WriteError, path: &Path, skipped: &mut Vec<String>) -> Result<(), String> {
+    match error {
+        WriteError::Quota(_) | WriteError::Failed(_) => Err(format!("{}: {}", path.display(), error)),
+        _ => {
+            skipped.push(format!("{}: {}", path.display(), error));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::reset;
+    use std::os::unix::fs::{ symlink, PermissionsExt };
+
+    #[test]
+    fn test_import_tree_with_filters_and_symlinks() {
+        let (src, db_path) = ("./test_import_src", "./test_db_import");
+        reset(src);
+        reset(db_path);
+
+        fs::create_dir_all(format!("{}/docs/empty", src)).unwrap();
+        fs::create_dir_all(format!("{}/.git", src)).unwrap();
+        fs::create_dir_all(format!("{}/target", src)).unwrap();
+        fs::write(format!("{}/docs/readme.md", src), b"# Hello").unwrap();
+        fs::write(format!("{}/run.sh", src), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(format!("{}/run.sh", src), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(format!("{}/debug.log", src), b"noise").unwrap();
+        fs::write(format!("{}/.gitignore", src), b"*.log\n").unwrap();
+        fs::write(format!("{}/.git/HEAD", src), b"ref: refs/heads/main").unwrap();
+        fs::write(format!("{}/target/out.bin", src), b"build output").unwrap();
+        symlink("docs/readme.md", format!("{}/latest", src)).unwrap();
+
+        let manager = FileManager::new(db_path);
+        let options = ImportOptions {
+            prefix: "/backup/proj/".to_string(),
+            exclude: vec!["target/".to_string()],
+            gitignore: true,
+            ..ImportOptions::default()
+        };
+        let report = import_tree(&manager, Path::new(src), &options).unwrap();
+
+        // 1. Structure, content and metadata; ignored and excluded entries are left out
+        assert_eq!((report.files, report.symlinks), (3, 1)); // readme, run.sh, .gitignore
+        assert_eq!(manager.read_file("backup/proj/docs/readme.md").unwrap(), b"# Hello");
+        assert_eq!(manager.get_file_metadata("backup/proj/docs/empty"), Some((0, FileKind::Directory)));
+        let (_, _, meta) = manager.stat("backup/proj/run.sh").unwrap();
+        assert_eq!(meta.mode, 0o750);
+        assert!(meta.mtime().is_some());
+        for gone in ["debug.log", ".git", ".git/HEAD", "target", "target/out.bin"] {
+            assert!(manager.get_file_metadata(&format!("backup/proj/{}", gone)).is_none(), "{}", gone);
+        }
+
+        // 2. The link is kept as a link unless following is asked for
+        let (size, kind, _) = manager.stat("backup/proj/latest").unwrap();
+        assert_eq!((size, kind), (14, FileKind::Symlink));
+        assert_eq!(manager.read_file("backup/proj/latest").unwrap(), b"docs/readme.md");
+        let options = ImportOptions {
+            prefix: "followed".to_string(),
+            follow_symlinks: true,
+            include: vec!["latest".to_string(), "*.log".to_string()],
+            ..ImportOptions::default()
+        };
+        let report = import_tree(&manager, Path::new(src), &options).unwrap();
+
+        // 3. Includes pick files by name; no gitignore now, and no directory
+        // is made except the prefix (which takes the source's own metadata)
+        assert_eq!((report.files, report.symlinks, report.directories), (2, 0, 1));
+        assert_eq!(manager.read_file("followed/latest").unwrap(), b"# Hello");
+        assert_eq!(manager.get_file_metadata("followed/debug.log"), Some((5, FileKind::File)));
+        assert!(manager.get_file_metadata("followed/docs").is_none());
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_import_never_puts_entries_under_a_file() {
+        let (src, db_path) = ("./test_import_orphans_src", "./test_db_import_orphans");
+        reset(src);
+        reset(db_path);
+        fs::create_dir_all(format!("{}/docs/empty", src)).unwrap();
+        fs::write(format!("{}/docs/readme.md", src), b"# Hello").unwrap();
+        fs::write(format!("{}/top.txt", src), b"top").unwrap();
+
+        let manager = FileManager::new(db_path);
+        manager.write_file("taken", b"a file").unwrap();
+        manager.create_directory("dest").unwrap();
+        manager.write_file("dest/docs", b"also a file").unwrap();
+
+        // 1. A prefix running through a file fails up front
+        let options = ImportOptions { prefix: "taken/inside".to_string(), ..ImportOptions::default() };
+        let error = import_tree(&manager, Path::new(src), &options).unwrap_err();
+        assert_eq!(error, "taken: Not a directory");
+        assert!(manager.get_file_metadata("taken/inside").is_none());
+
+        // 2. Entries whose directory is a file are skipped; the rest still lands
+        let options = ImportOptions { prefix: "dest".to_string(), ..ImportOptions::default() };
+        let report = import_tree(&manager, Path::new(src), &options).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.skipped.len(), 2, "{:?}", report.skipped);
+        assert!(report.skipped.iter().all(|skip| skip.ends_with("Not a directory")));
+        assert_eq!(manager.read_file("dest/docs").unwrap(), b"also a file");
+        assert_eq!(manager.read_file("dest/top.txt").unwrap(), b"top");
+        for orphan in ["dest/docs/readme.md", "dest/docs/empty"] {
+            assert!(manager.get_file_metadata(orphan).is_none(), "{}", orphan);
+        }
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(db_path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+// src/locks.rs
+use std::collections::{ HashMap, HashSet };
+
+/// An advisory byte-range lock as FUSE describes it. `end` is inclusive, and
+/// a lock "to the end of the file" just has a huge `end`. flock(2) locks come
+/// through the same way: the whole file, owned by the open file description.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    /// F_WRLCK; otherwise a shared F_RDLCK
+    pub exclusive: bool,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner && self.overlaps(other.start, other.end) && (self.exclusive || other.exclusive)
+    }
+}
+
+struct FileLocks<W> {
+    held: Vec<Lock>,
+    // Blocked F_SETLKW requests, granted in arrival order
+    waiting: Vec<(Lock, W)>,
+}
+
+impl<W> Default for FileLocks<W> {
+    fn default() -> Self {
+        FileLocks { held: Vec::new(), waiting: Vec::new() }
+    }
+}
+
+/// Every advisory lock on the mount, by inode. A blocking request that can't
+/// be granted parks its `W` (the FUSE reply) instead of a thread; `try_lock`,
+/// `unlock` and `release_owner` hand back the ones that got their lock.
+pub struct LockTable<W> {
+    files: HashMap<u64, FileLocks<W>>,
+}
+
+impl<W> Default for LockTable<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W> LockTable<W> {
+    pub fn new() -> Self {
+        LockTable { files: HashMap::new() }
+    }
+
+    /// F_GETLK: a lock of another owner that `lock` would conflict with
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.files.get(&ino)?.held.iter().find(|held| held.conflicts(lock)).copied()
+    }
+
+    /// F_SETLK: takes `lock`, replacing whatever its owner held in that range,
+    /// or returns the lock in the way. Replacing can free something (a write
+    /// lock downgraded to a read lock, a range shrunk), so the waiters that
+    /// hold their lock now are returned as with `unlock`.
+    pub fn try_lock(&mut self, ino: u64, lock: Lock) -> Result<Vec<W>, Lock> {
+        if let Some(blocker) = self.conflict(ino, &lock) {
+            return Err(blocker);
+        }
+        let held = &mut self.files.entry(ino).or_default().held;
+        carve(held, lock.owner, lock.start, lock.end);
+        held.push(lock);
+        Ok(self.grant_waiting(ino))
+    }
+
+    /// F_SETLKW that `try_lock` refused: `waiter` comes back out of `try_lock`,
+    /// `unlock` or `release_owner` once the lock is granted
+    pub fn wait(&mut self, ino: u64, lock: Lock, waiter: W) {
+        self.files.entry(ino).or_default().waiting.push((lock, waiter));
+    }
+
+    /// Whether waiting for `lock` would never end: an owner in its way is
+    /// (maybe through others, on any file) waiting for `lock.owner` (EDEADLK)
+    pub fn would_deadlock(&self, ino: u64, lock: &Lock) -> bool {
+        let mut seen = HashSet::new();
+        let mut blockers = self.blockers(ino, lock);
+        while let Some(owner) = blockers.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !seen.insert(owner) {
+                continue;
+            }
+            for (file, locks) in &self.files {
+                for (wanted, _) in locks.waiting.iter().filter(|(wanted, _)| wanted.owner == owner) {
+                    blockers.extend(self.blockers(*file, wanted));
+                }
+            }
+        }
+        false
+    }
+
+    /// F_UNLCK: drops what `owner` holds in [start, end]. Returns the waiters
+    /// that hold their lock now.
+    pub fn unlock(&mut self, ino: u64, owner: u64, start: u64, end: u64) -> Vec<W> {
+        let Some(locks) = self.files.get_mut(&ino) else {
+            return Vec::new();
+        };
+        carve(&mut locks.held, owner, start, end);
+        self.grant_waiting(ino)
+    }
+
+    /// The owner closed the file (or its flock handle was released): drops
+    /// all its locks on it. Returns the waiters granted as a result, and the
+    /// owner's own pending requests, which are cancelled.
+    pub fn release_owner(&mut self, ino: u64, owner: u64) -> (Vec<W>, Vec<W>) {
+        let Some(locks) = self.files.get_mut(&ino) else {
+            return (Vec::new(), Vec::new());
+        };
+        carve(&mut locks.held, owner, 0, u64::MAX);
+        let (theirs, others) = std::mem::take(&mut locks.waiting)
+            .into_iter()
+            .partition(|(lock, _)| lock.owner == owner);
+        locks.waiting = others;
+        let cancelled = theirs.into_iter().map(|(_, waiter)| waiter).collect();
+        (self.grant_waiting(ino), cancelled)
+    }
+
+    fn blockers(&self, ino: u64, lock: &Lock) -> Vec<u64> {
+        self.files.get(&ino).map_or_else(Vec::new, |locks| {
+            locks.held
+                .iter()
+                .filter(|held| held.conflicts(lock))
+                .map(|held| held.owner)
+                .collect()
+        })
+    }
+
+    fn grant_waiting(&mut self, ino: u64) -> Vec<W> {
+        let Some(locks) = self.files.get_mut(&ino) else {
+            return Vec::new();
+        };
+        let mut granted = Vec::new();
+        for (lock, waiter) in std::mem::take(&mut locks.waiting) {
+            if locks.held.iter().any(|held| held.conflicts(&lock)) {
+                locks.waiting.push((lock, waiter));
+            } else {
+                carve(&mut locks.held, lock.owner, lock.start, lock.end);
+                locks.held.push(lock);
+                granted.push(waiter);
+            }
+        }
+        if locks.held.is_empty() && locks.waiting.is_empty() {
+            self.files.remove(&ino);
+        }
+        granted
+    }
+}
+
+// Cuts [start, end] out of `owner`'s locks, splitting any that straddle it
+fn carve(held: &mut Vec<Lock>, owner: u64, start: u64, end: u64) {
+    let mut kept = Vec::with_capacity(held.len() + 1);
+    for lock in held.drain(..) {
+        if lock.owner != owner || !lock.overlaps(start, end) {
+            kept.push(lock);
+            continue;
+        }
+        if lock.start < start {
+            kept.push(Lock { end: start - 1, ..lock });
+        }
+        if lock.end > end {
+            kept.push(Lock { start: end + 1, ..lock });
+        }
+    }
+    *held = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, exclusive: bool) -> Lock {
+        Lock { owner, pid: owner as u32, start, end, exclusive }
+    }
+
+    #[test]
+    fn test_shared_exclusive_and_ranges() {
+        let mut table: LockTable<()> = LockTable::new();
+        table.try_lock(1, lock(1, 0, 99, false)).unwrap();
+
+        // Readers share, a writer is refused, and other ranges and files are free
+        table.try_lock(1, lock(2, 50, 149, false)).unwrap();
+        assert_eq!(table.try_lock(1, lock(3, 0, 9, true)), Err(lock(1, 0, 99, false)));
+        table.try_lock(1, lock(3, 150, u64::MAX, true)).unwrap();
+        table.try_lock(2, lock(3, 0, 9, true)).unwrap();
+        assert_eq!(table.conflict(1, &lock(4, 200, 200, false)).map(|l| l.owner), Some(3));
+
+        // Re-locking your own range replaces it; unlocking the middle splits it
+        table.try_lock(1, lock(1, 0, 99, true)).unwrap_err(); // Owner 2 still reads 50..
+        table.unlock(1, 2, 0, u64::MAX);
+        table.try_lock(1, lock(1, 0, 99, true)).unwrap();
+        table.unlock(1, 1, 40, 59);
+        assert!(table.conflict(1, &lock(2, 40, 59, true)).is_none());
+        assert_eq!(table.conflict(1, &lock(2, 30, 45, false)), Some(lock(1, 0, 39, true)));
+        assert_eq!(table.conflict(1, &lock(2, 55, 65, false)), Some(lock(1, 60, 99, true)));
+    }
+
+    #[test]
+    fn test_waiters_and_owner_cleanup() {
+        let mut table = LockTable::new();
+        table.try_lock(7, lock(1, 0, u64::MAX, true)).unwrap();
+
+        // Two blocked requests; both are readers, so both get in together
+        table.wait(7, lock(2, 0, 10, false), "reader 2");
+        table.wait(7, lock(3, 5, 20, false), "reader 3");
+        assert!(table.unlock(7, 1, 0, 4).is_empty()); // Still covered
+        assert_eq!(table.unlock(7, 1, 5, u64::MAX), vec!["reader 2", "reader 3"]);
+
+        // A writer waits for both readers; closing cancels a waiter's own request
+        table.wait(7, lock(4, 0, 10, true), "writer 4");
+        table.wait(7, lock(2, 30, 30, true), "writer 2");
+        let (granted, cancelled) = table.release_owner(7, 2);
+        assert_eq!((granted, cancelled), (vec![], vec!["writer 2"]));
+        let (granted, _) = table.release_owner(7, 3);
+        assert_eq!(granted, vec!["writer 4"]);
+
+        // Downgrading a write lock to a read lock lets a waiting reader in
+        table.wait(7, lock(5, 0, 0, false), "reader 5");
+        assert_eq!(table.try_lock(7, lock(4, 0, 10, false)).unwrap(), vec!["reader 5"]);
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let mut table = LockTable::new();
+        table.try_lock(1, lock(1, 0, 0, true)).unwrap();
+        table.try_lock(2, lock(2, 0, 0, true)).unwrap();
+
+        // Owner 1 waits for owner 2's file; owner 2 then asking for owner 1's would deadlock
+        assert!(!table.would_deadlock(2, &lock(1, 0, 0, true)));
+        table.wait(2, lock(1, 0, 0, true), ());
+        assert!(table.would_deadlock(1, &lock(2, 0, 0, true)));
+        assert!(!table.would_deadlock(1, &lock(3, 0, 0, true)));
+    }
+}
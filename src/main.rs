@@ -1,33 +1,143 @@
 // src/main.rs
+mod archive;
+mod chunk_cache;
 mod chunker;
+mod prechunker;
+mod compression;
+mod locks;
+mod quota;
+mod recipe;
+mod repo;
+mod stats;
 mod storage;
+mod sync;
 mod file_manager;
+mod export;
+mod import;
 mod fuse_handler;
+mod prefetch;
+mod worker_pool;
+#[cfg(test)]
+mod test_util;
 
 use clap::{ Parser, Subcommand };
 use file_manager::FileManager;
 use std::path::PathBuf;
 use std::fs;
 use std::io::Write; // Needed for flushing output
-use crate::file_manager::FileRecipe;
-use fuser::{ MountOption, Session }; // Ensure you have fuser imports
+use crate::recipe::{ FileKind, FileRecipe };
+use crate::compression::CompressionPolicy;
+use crate::fuse_handler::{ FsConfig, WritebackPolicy };
+use crate::quota::QuotaLimits;
+use crate::stats::RepoStats;
+use crate::storage::HashAlgorithm;
 
 // 1. Define the Command Line Interface (CLI)
 #[derive(Parser)]
 #[command(name = "BetterFS")]
 #[command(about = "A deduplicating, content-addressable filesystem", long_about = None)]
 struct Cli {
+    /// Repository to use: a path, or a name from the config file
+    /// (default: $BETTERFS_REPO, then the config's `default`, then ./my_storage)
+    #[arg(long, global = true)]
+    repo: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Create a repository (other commands refuse a directory without one)
+    Init {
+        /// Where to create it (default: the repository selected as for other commands)
+        path: Option<PathBuf>,
+    },
     /// Save a file to BetterFS
     Write {
         /// The path to the file you want to upload
         file_path: PathBuf,
     },
+    /// Copy a directory tree into BetterFS, keeping paths, modes, mtimes and symlinks
+    Import {
+        /// Directory (or single file) on the host to import
+        source: PathBuf,
+        /// Directory inside BetterFS to put it under (default: the root)
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Store what symlinks point to instead of the links themselves
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Only import files matching this glob (repeatable), e.g. --include '*.rs'
+        #[arg(long)]
+        include: Vec<String>,
+        /// Skip files and directories matching this glob (repeatable), e.g. --exclude 'target/'
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Import files that .gitignore would exclude, and .git itself
+        #[arg(long)]
+        no_gitignore: bool,
+    },
+    /// Store every entry of a tar stream, with modes, mtimes, symlinks and hard links
+    ImportTar {
+        /// Tarball to read (default: stdin)
+        archive: Option<PathBuf>,
+        /// Directory inside BetterFS to put the entries under (default: the root)
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    /// Write a file or directory tree as a tar stream, one chunk in memory at a time
+    ExportTar {
+        /// File or directory inside BetterFS ("/" for everything)
+        path: String,
+        /// Where to write the tarball (default: stdout)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Bring a directory inside BetterFS up to date with a host directory,
+    /// ingesting only files whose size, mtime (or, with --checksum, content) changed
+    Sync {
+        /// Host directory to sync from
+        source: PathBuf,
+        /// Directory inside BetterFS to sync into ("/" for the root)
+        dest_prefix: String,
+        /// Compare file content too (reads every source file, stores only changes)
+        #[arg(long)]
+        checksum: bool,
+        /// Delete entries whose source file or directory is gone
+        #[arg(long)]
+        delete: bool,
+        /// List the planned changes without making them
+        #[arg(long, short = 'n')]
+        dry_run: bool,
+        /// Store what symlinks point to instead of the links themselves
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Only sync files matching this glob (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+        /// Skip files and directories matching this glob (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Sync files that .gitignore would exclude, and .git itself
+        #[arg(long)]
+        no_gitignore: bool,
+    },
+    /// Restore a file or directory tree to the host, with modes, mtimes and symlinks
+    Export {
+        /// File or directory inside BetterFS ("/" for everything)
+        path: String,
+        /// Host directory to restore it into (`docs` lands in <dest>/docs)
+        dest: PathBuf,
+        /// Replace files that already exist at the destination
+        #[arg(long, conflicts_with = "skip_existing")]
+        overwrite: bool,
+        /// Leave files that already exist at the destination alone
+        #[arg(long)]
+        skip_existing: bool,
+        /// Chunk fetch threads (default: one per CPU)
+        #[arg(long, default_value_t = 0)]
+        threads: usize,
+    },
     /// Read a file back from BetterFS
     Read {
         /// The name of the file inside BetterFS
@@ -38,23 +148,136 @@ enum Commands {
     Mount {
         /// The folder to mount to (e.g., ./mnt)
         mount_point: String,
+        /// Size of the decompressed-chunk cache in MiB (0 disables it)
+        #[arg(long, default_value_t = 64)]
+        cache_size: usize,
+        /// When open files are committed: on-close, on-fsync or periodic:<seconds>
+        #[arg(long, default_value = "on-close")]
+        writeback: WritebackPolicy,
+        /// Mount read-only: every change fails with EROFS
+        #[arg(long)]
+        read_only: bool,
+        /// Let other users access the mount (needs user_allow_other in /etc/fuse.conf)
+        #[arg(long, conflicts_with = "allow_root")]
+        allow_other: bool,
+        /// Let root access the mount as well as the user who mounted it
+        #[arg(long)]
+        allow_root: bool,
+        /// Have the kernel check file permissions against the reported modes
+        #[arg(long)]
+        default_permissions: bool,
+        /// Owner reported for every file and directory
+        #[arg(long, default_value_t = 1000)]
+        uid: u32,
+        /// Group reported for every file and directory
+        #[arg(long, default_value_t = 1000)]
+        gid: u32,
+        /// Filesystem name shown in mount tables and `df`
+        #[arg(long, default_value = "betterfs")]
+        fsname: String,
+        /// Filesystem subtype (shown as fuse.<subtype>)
+        #[arg(long)]
+        subtype: Option<String>,
     },
     /// Inspect the internal database (for debugging)
     Inspect,
     /// Run Garbage Collection to remove unused chunks
     Gc,
+    /// Verify that every referenced chunk exists and matches its hash
+    Fsck,
+    /// Train a zstd dictionary from stored chunks and use it for new writes
+    TrainDict {
+        /// Maximum number of chunks to sample
+        #[arg(long, default_value_t = 2000)]
+        samples: usize,
+        /// Maximum dictionary size in bytes
+        #[arg(long, default_value_t = 112_640)]
+        size: usize,
+    },
+    /// Show or change repository settings, e.g. `config hash-algorithm blake3`,
+    /// `config compression zstd:19`, `config compression@media/ none`, `config min-savings 10`,
+    /// `config dictionary none`, `config quota 500G`, `config format-aware on`
+    Config {
+        /// Setting to show or change (omit to list all)
+        key: Option<String>,
+        /// New value for the setting
+        value: Option<String>,
+    },
+    /// Report logical vs. stored bytes, dedup and compression ratios, and chunk sizes
+    Stats {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the bytes each entry under a path owns exclusively vs. shares with others
+    Du {
+        /// File or directory inside BetterFS (default: everything)
+        #[arg(default_value = "/")]
+        path: String,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show usage against quotas, or set the quota on a directory prefix
+    /// ("/" is the whole repository), e.g. `quota teams/a --logical 10G`
+    Quota {
+        /// Directory prefix to change (omit to list all quotas)
+        prefix: Option<String>,
+        /// Limit on the sum of file sizes ("none" to lift it)
+        #[arg(long)]
+        logical: Option<String>,
+        /// Limit on deduplicated, compressed bytes on disk ("none" to lift it)
+        #[arg(long)]
+        physical: Option<String>,
+        /// Drop every limit on the prefix
+        #[arg(long)]
+        remove: bool,
+    },
 }
 
 fn main() {
     let args = Cli::parse();
 
-    // Initialize the engine in a folder named "my_storage"
-    // This creates a permanent database on your disk.
-    let storage_path = "./my_storage";
-    let manager = FileManager::new(storage_path);
-    let db_path = format!("{}/metadata_db", storage_path);
+    // Find the repository: --repo, $BETTERFS_REPO, the config file, ./my_storage
+    let config = match repo::config_path() {
+        Some(path) => repo::Config::load(&path),
+        None => Ok(repo::Config::default()),
+    };
+    let env_repo = std::env::var(repo::REPO_ENV).ok().filter(|value| !value.is_empty());
+    let storage_path = match config.and_then(|config| config.resolve(args.repo.as_deref(), env_repo.as_deref())) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Commands::Init { path } = &args.command {
+        let path = path.as_ref().unwrap_or(&storage_path);
+        match repo::init(path) {
+            Ok(true) => println!("Adopted the existing store at {} as a repository", path.display()),
+            Ok(false) => {
+                // Open it once so the store and database exist from the start
+                drop(FileManager::new(&path.to_string_lossy()));
+                println!("Initialized an empty BetterFS repository in {}", path.display());
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Err(e) = repo::check(&storage_path) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+
+    let mut manager = FileManager::new(&storage_path.to_string_lossy());
+    let db_path = storage_path.join("metadata_db");
 
     match args.command {
+        Commands::Init { .. } => unreachable!("handled above"),
         Commands::Write { file_path } => {
             // 1. Read data from your REAL hard drive
             let data = match fs::read(&file_path) {
@@ -74,6 +297,146 @@ fn main() {
                 Err(e) => eprintln!("Error: {}", e),
             }
         }
+        Commands::Import { source, prefix, follow_symlinks, include, exclude, no_gitignore } => {
+            let options = import::ImportOptions {
+                prefix,
+                follow_symlinks,
+                include,
+                exclude,
+                gitignore: !no_gitignore,
+            };
+            match import::import_tree(&manager, &source, &options) {
+                Ok(report) => {
+                    for skipped in &report.skipped {
+                        eprintln!("Skipped {}", skipped);
+                    }
+                    println!(
+                        "Imported {} files ({}), {} directories and {} symlinks; skipped {}",
+                        report.files,
+                        format_size(report.bytes),
+                        report.directories,
+                        report.symlinks,
+                        report.skipped.len()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Sync {
+            source,
+            dest_prefix,
+            checksum,
+            delete,
+            dry_run,
+            follow_symlinks,
+            include,
+            exclude,
+            no_gitignore,
+        } => {
+            let import = import::ImportOptions {
+                prefix: dest_prefix,
+                follow_symlinks,
+                include,
+                exclude,
+                gitignore: !no_gitignore,
+            };
+            let options = sync::SyncOptions { import, checksum, delete, dry_run };
+            match sync::sync_tree(&manager, &source, &options) {
+                Ok(report) => {
+                    for change in &report.changes {
+                        println!("{}", change);
+                    }
+                    for skipped in &report.skipped {
+                        eprintln!("Skipped {}", skipped);
+                    }
+                    let verb = if dry_run { "Would make" } else { "Made" };
+                    println!(
+                        "{} {} changes ({} ingested); {} unchanged, {} skipped",
+                        verb,
+                        report.changes.len(),
+                        format_size(report.bytes),
+                        report.unchanged,
+                        report.skipped.len()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::ImportTar { archive, prefix } => {
+            let imported = match archive {
+                Some(file) => fs::File::open(&file)
+                    .map_err(|e| format!("Could not open {}: {}", file.display(), e))
+                    .and_then(|file| archive::import_tar(&manager, std::io::BufReader::new(file), &prefix)),
+                None => archive::import_tar(&manager, std::io::stdin().lock(), &prefix),
+            };
+            match imported {
+                Ok(report) => {
+                    for skipped in &report.skipped {
+                        eprintln!("Skipped {}", skipped);
+                    }
+                    eprintln!(
+                        "Imported {} files ({}), {} directories and {} symlinks; skipped {}",
+                        report.files,
+                        format_size(report.bytes),
+                        report.directories,
+                        report.symlinks,
+                        report.skipped.len()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::ExportTar { path, output } => {
+            // The tarball may be going to stdout, so the summary goes to stderr
+            let exported = match output {
+                Some(file) => fs::File::create(&file)
+                    .map_err(|e| format!("Could not create {}: {}", file.display(), e))
+                    .and_then(|file| archive::export_tar(&manager, &path, std::io::BufWriter::new(file))),
+                None => archive::export_tar(&manager, &path, std::io::stdout().lock()),
+            };
+            match exported {
+                Ok(entries) => eprintln!("Exported {} entries", entries),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Export { path, dest, overwrite, skip_existing, threads } => {
+            let existing = match (overwrite, skip_existing) {
+                (true, _) => export::Existing::Overwrite,
+                (_, true) => export::Existing::Skip,
+                _ => export::Existing::Fail,
+            };
+            match export::export_tree(&manager, &path, &dest, &export::ExportOptions { existing, threads }) {
+                Ok(report) => {
+                    for skipped in &report.skipped {
+                        eprintln!("Skipped {}", skipped);
+                    }
+                    println!(
+                        "Exported {} files ({}), {} directories and {} symlinks; skipped {}",
+                        report.files,
+                        format_size(report.bytes),
+                        report.directories,
+                        report.symlinks,
+                        report.skipped.len()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
         Commands::Read { file_name } => {
             // 1. Ask BetterFS for the bytes
             match manager.read_file(&file_name) {
@@ -95,7 +458,19 @@ fn main() {
                 }
             }
         }
-        Commands::Mount { mount_point } => {
+        Commands::Mount {
+            mount_point,
+            cache_size,
+            writeback,
+            read_only,
+            allow_other,
+            allow_root,
+            default_permissions,
+            uid,
+            gid,
+            fsname,
+            subtype,
+        } => {
             println!("Mounting BetterFS to {}...", mount_point);
             println!("(Press Ctrl+C to unmount)");
 
@@ -103,13 +478,27 @@ fn main() {
             fs::create_dir_all(&mount_point).unwrap();
 
             // Start the FUSE Driver
-            let options = vec![
-                fuser::MountOption::RW, // Read-Only
-                fuser::MountOption::FSName("betterfs".to_string()),
+            let mut options = vec![
+                if read_only { fuser::MountOption::RO } else { fuser::MountOption::RW },
+                fuser::MountOption::FSName(fsname),
                 fuser::MountOption::AutoUnmount // Helps clean up on exit
             ];
+            if let Some(subtype) = subtype {
+                options.push(fuser::MountOption::Subtype(subtype));
+            }
+            if allow_other {
+                options.push(fuser::MountOption::AllowOther);
+            }
+            if allow_root {
+                options.push(fuser::MountOption::AllowRoot);
+            }
+            if default_permissions {
+                options.push(fuser::MountOption::DefaultPermissions);
+            }
 
-            let fs_impl = fuse_handler::BetterFS::new(manager);
+            manager.set_cache_capacity(cache_size * 1024 * 1024);
+            let config = FsConfig { writeback, read_only, uid, gid };
+            let fs_impl = fuse_handler::BetterFS::new(manager, config);
 
             fuser::mount2(fs_impl, mount_point, &options).unwrap();
         }
@@ -118,30 +507,29 @@ fn main() {
             // Open the DB directly for reading
             let db = sled::open(&db_path).expect("Failed to open DB");
 
-            for item in db.iter() {
-                if let Ok((key, value)) = item {
-                    let key_str = String::from_utf8_lossy(&key);
-
-                    // Try to decode as a FileRecipe
-                    // FIX 4: Use 'crate::file_manager' instead of 'better_fs::...'
-                    match bincode::deserialize::<FileRecipe>(&value) {
-                        Ok(recipe) => {
-                            let kind_str = match recipe.kind {
-                                file_manager::FileKind::Directory => "DIR",
-                                file_manager::FileKind::File => "FILE",
-                            };
-                            println!(
-                                "[{}] {} \t(Size: {} bytes, Chunks: {})",
-                                kind_str,
-                                key_str,
-                                recipe.file_size,
-                                recipe.chunks.len()
-                            );
-                        }
-                        Err(_) => {
-                            // If it fails, it might be raw data or something else
-                            println!("[???] {} \t(Raw Data)", key_str);
-                        }
+            for (key, value) in db.iter().flatten() {
+                let key_str = String::from_utf8_lossy(&key);
+
+                // Try to decode as a FileRecipe
+                // FIX 4: Use 'crate::file_manager' instead of 'better_fs::...'
+                match FileRecipe::decode(&value) {
+                    Ok((recipe, _)) => {
+                        let kind_str = match recipe.kind {
+                            FileKind::Directory => "DIR",
+                            FileKind::File => "FILE",
+                            FileKind::Symlink => "LINK",
+                        };
+                        println!(
+                            "[{}] {} \t(Size: {} bytes, Chunks: {})",
+                            kind_str,
+                            key_str,
+                            recipe.file_size,
+                            recipe.chunks.len()
+                        );
+                    }
+                    Err(_) => {
+                        // If it fails, it might be raw data or something else
+                        println!("[???] {} \t(Raw Data)", key_str);
                     }
                 }
             }
@@ -155,5 +543,314 @@ fn main() {
                 Err(e) => eprintln!("GC Failed: {}", e),
             }
         }
+
+        Commands::Fsck => {
+            match manager.run_fsck() {
+                Ok(report) => {
+                    println!(
+                        "Checked {} files, {} unique chunks.",
+                        report.files_checked,
+                        report.chunks_checked
+                    );
+                    for (file, hash) in &report.missing {
+                        println!("MISSING: chunk {} (used by '{}')", hash, file);
+                    }
+                    for hash in &report.corrupt {
+                        println!("CORRUPT: chunk {}", hash);
+                    }
+                    if report.orphans > 0 {
+                        println!("{} orphaned chunks can be reclaimed with `gc`.", report.orphans);
+                    }
+                    if report.is_clean() {
+                        println!("No problems found.");
+                    } else {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => eprintln!("Fsck Failed: {}", e),
+            }
+        }
+
+        Commands::TrainDict { samples, size } => {
+            match manager.train_dictionary(samples, size) {
+                Ok(id) => println!("Trained dictionary {:08x}; new chunks will use it.", id),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+
+        Commands::Config { key, value } => {
+            match (key.as_deref(), value) {
+                (None, _) => {
+                    println!("hash-algorithm = {}", manager.hash_algorithm());
+                    println!("compression = {}", manager.compression());
+                    println!("min-savings = {}%", manager.min_savings_percent());
+                    println!("dictionary = {}", format_dictionary(manager.dictionary()));
+                    println!("quota = {}", format_quota(&manager));
+                    println!("format-aware = {}", format_switch(manager.format_aware()));
+                    let stored: Vec<String> = manager
+                        .stored_dictionaries()
+                        .iter()
+                        .map(|id| format!("{:08x}", id))
+                        .collect();
+                    if !stored.is_empty() {
+                        println!("# stored dictionaries: {}", stored.join(", "));
+                    }
+                    for (prefix, policy) in manager.compression_prefixes() {
+                        println!("compression@{} = {}", prefix, policy);
+                    }
+                }
+                (Some(key), None) => {
+                    match key {
+                        "hash-algorithm" => println!("{}", manager.hash_algorithm()),
+                        "compression" => println!("{}", manager.compression()),
+                        "min-savings" => println!("{}%", manager.min_savings_percent()),
+                        "dictionary" => println!("{}", format_dictionary(manager.dictionary())),
+                        "quota" => println!("{}", format_quota(&manager)),
+                        "format-aware" => println!("{}", format_switch(manager.format_aware())),
+                        _ if key.starts_with("compression@") => {
+                            let prefix = &key["compression@".len()..];
+                            let policy = manager.prefix_compression(prefix);
+                            println!("{}", policy.unwrap_or(manager.compression()));
+                        }
+                        _ => eprintln!("Error: Unknown setting '{}'", key),
+                    }
+                }
+                (Some(key), Some(value)) => {
+                    let result = match key {
+                        "hash-algorithm" =>
+                            value
+                                .parse::<HashAlgorithm>()
+                                .and_then(|algorithm| manager.set_hash_algorithm(algorithm)),
+                        "compression" =>
+                            value
+                                .parse::<CompressionPolicy>()
+                                .and_then(|policy| manager.set_compression(policy)),
+                        "min-savings" =>
+                            value
+                                .trim_end_matches('%')
+                                .parse::<u8>()
+                                .map_err(|_| format!("Invalid percentage '{}'", value))
+                                .and_then(|percent| manager.set_min_savings_percent(percent)),
+                        "dictionary" if value == "none" => manager.set_dictionary(None),
+                        "dictionary" =>
+                            u32
+                                ::from_str_radix(&value, 16)
+                                .map_err(|_| format!("Invalid dictionary ID '{}'", value))
+                                .and_then(|id| manager.set_dictionary(Some(id))),
+                        "quota" if value == "none" => manager.set_quota(None),
+                        "quota" => parse_size(&value).and_then(|bytes| manager.set_quota(Some(bytes))),
+                        "format-aware" =>
+                            match value.as_str() {
+                                "on" => Ok(true),
+                                "off" => Ok(false),
+                                _ => Err(format!("format-aware must be on or off, got '{}'", value)),
+                            }.and_then(|enabled| manager.set_format_aware(enabled)),
+                        // "inherit" drops the override for that prefix
+                        _ if key.starts_with("compression@") => {
+                            let prefix = &key["compression@".len()..];
+                            if value == "inherit" {
+                                manager.set_prefix_compression(prefix, None)
+                            } else {
+                                value
+                                    .parse::<CompressionPolicy>()
+                                    .and_then(|policy| manager.set_prefix_compression(prefix, Some(policy)))
+                            }
+                        }
+                        _ => Err(format!("Unknown setting '{}'", key)),
+                    };
+                    match result {
+                        Ok(()) => println!("{} set to {}", key, value),
+                        Err(e) => eprintln!("Error: {}", e),
+                    }
+                }
+            }
+        }
+
+        Commands::Stats { json } => {
+            match manager.repo_stats() {
+                Ok(stats) if json => print_json(&stats),
+                Ok(stats) => print_stats(&stats),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+
+        Commands::Du { path, json } => {
+            match manager.disk_usage(&path) {
+                Ok(entries) if json => print_json(&entries),
+                Ok(entries) if entries.is_empty() => eprintln!("Error: '{}' not found", path),
+                Ok(entries) => {
+                    println!("{:>12} {:>12} {:>12} {:>8}  PATH", "LOGICAL", "EXCLUSIVE", "SHARED", "FILES");
+                    for entry in entries {
+                        println!(
+                            "{:>12} {:>12} {:>12} {:>8}  {}",
+                            format_size(entry.logical_bytes),
+                            format_size(entry.exclusive_bytes),
+                            format_size(entry.shared_bytes),
+                            entry.files,
+                            entry.path
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+
+        Commands::Quota { prefix, logical, physical, remove } => {
+            if let Err(e) = run_quota(&mut manager, prefix, logical, physical, remove) {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+}
+
+fn print_stats(stats: &RepoStats) {
+    println!(
+        "Files:             {} ({} directories, {} symlinks)",
+        stats.files,
+        stats.directories,
+        stats.symlinks
+    );
+    println!("Logical size:      {}", format_size(stats.logical_bytes));
+    println!("In holes:          {}", format_size(stats.hole_bytes));
+    println!("Unique chunks:     {} of {} referenced", stats.unique_chunks, stats.chunk_refs);
+    println!("Unique chunk size: {}", format_size(stats.unique_bytes));
+    println!("Stored on disk:    {}", format_size(stats.stored_bytes));
+    println!("Dedup ratio:       {:.2}x", stats.dedup_ratio);
+    println!("Compression ratio: {:.2}x", stats.compression_ratio);
+    if stats.histogram.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{:>23} {:>8} {:>12}", "CHUNK SIZE", "CHUNKS", "BYTES");
+    let widest = stats.histogram.iter().map(|b| b.chunks).max().unwrap_or(1);
+    for bucket in &stats.histogram {
+        let range = format!("{} - {}", format_size(bucket.min_size), format_size(bucket.max_size));
+        let bar = "#".repeat((((bucket.chunks * 40) / widest) as usize).max(1));
+        println!("{:>23} {:>8} {:>12}  {}", range, bucket.chunks, format_size(bucket.bytes), bar);
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+fn run_quota(
+    manager: &mut FileManager,
+    prefix: Option<String>,
+    logical: Option<String>,
+    physical: Option<String>,
+    remove: bool
+) -> Result<(), String> {
+    let Some(prefix) = prefix else {
+        // 1. No prefix: list every quota with its usage
+        if manager.quotas().is_empty() {
+            println!("No quotas set.");
+            return Ok(());
+        }
+        println!("{:<30} {:>24} {:>24}", "PREFIX", "LOGICAL", "PHYSICAL");
+        for (prefix, limits) in manager.quotas() {
+            let usage = manager.quota_usage(prefix);
+            let column = |used: u64, limit: Option<u64>| {
+                format!("{} / {}", format_size(used), limit.map_or("none".to_string(), format_size))
+            };
+            println!(
+                "{:<30} {:>24} {:>24}",
+                format!("/{}", prefix),
+                column(usage.logical, limits.logical),
+                column(usage.physical, limits.physical)
+            );
+        }
+        return Ok(());
+    };
+
+    // 2. Change the limits on one prefix, keeping whichever isn't given
+    let limits = if remove {
+        None
+    } else {
+        let parse = |value: Option<String>, current: Option<u64>| -> Result<Option<u64>, String> {
+            match value.as_deref() {
+                None => Ok(current),
+                Some("none") => Ok(None),
+                Some(size) => parse_size(size).map(Some),
+            }
+        };
+        let current = manager.quota_limits(&prefix).unwrap_or_default();
+        let limits = QuotaLimits {
+            logical: parse(logical, current.logical)?,
+            physical: parse(physical, current.physical)?,
+        };
+        (limits != QuotaLimits::default()).then_some(limits)
+    };
+    manager.set_quota_limits(&prefix, limits)?;
+
+    // 3. Show the result, including the usage counted for a new prefix
+    match manager.quota_limits(&prefix) {
+        Some(limits) => {
+            let usage = manager.quota_usage(&prefix);
+            let show = |limit: Option<u64>| limit.map_or("none".to_string(), format_size);
+            println!(
+                "Quota on '{}': logical {} (using {}), physical {} (using {})",
+                prefix,
+                show(limits.logical),
+                format_size(usage.logical),
+                show(limits.physical),
+                format_size(usage.physical)
+            );
+        }
+        None => println!("Quota on '{}' removed", prefix),
+    }
+    Ok(())
+}
+
+fn format_dictionary(id: Option<u32>) -> String {
+    match id {
+        Some(id) => format!("{:08x}", id),
+        None => "none".to_string(),
+    }
+}
+
+fn format_switch(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+// "none" or the limit, with current usage alongside either way
+fn format_quota(manager: &FileManager) -> String {
+    let limit = manager.quota().map_or("none".to_string(), format_size);
+    match manager.space_report() {
+        Ok(space) => format!("{} ({} used)", limit, format_size(space.used_bytes)),
+        Err(_) => limit,
+    }
+}
+
+// Sizes are binary: "64K", "512M", "2G", "1T" (an optional trailing "B"/"iB" is fine)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim().trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let (digits, shift) = match trimmed.chars().last() {
+        Some('K' | 'k') => (&trimmed[..trimmed.len() - 1], 10),
+        Some('M' | 'm') => (&trimmed[..trimmed.len() - 1], 20),
+        Some('G' | 'g') => (&trimmed[..trimmed.len() - 1], 30),
+        Some('T' | 't') => (&trimmed[..trimmed.len() - 1], 40),
+        _ => (trimmed, 0),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or(format!("Invalid size '{}'", value))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
 }
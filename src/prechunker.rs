@@ -0,0 +1,225 @@
+// src/prechunker.rs
+
+// Format-aware pre-chunking: before the rolling hash looks at a file, find
+// where the members of a tar or zip archive start and force cuts there.
+// Ingest cuts at each member's header and at the start and end of its data,
+// so a member that appears in two archives (or two versions of one) becomes
+// the same chunks even when its header (offset, mtime, name) differs.
+// Anything that doesn't parse cleanly just gets fewer cuts, never wrong data.
+
+const TAR_BLOCK: usize = 512;
+const ZIP_LOCAL_HEADER: &[u8; 4] = b"PK\x03\x04";
+const ZIP_CENTRAL_HEADER: &[u8; 4] = b"PK\x01\x02";
+const ZIP_END_OF_DIRECTORY: &[u8; 4] = b"PK\x05\x06";
+// Fixed part of the end-of-central-directory record; a comment may follow
+const ZIP_EOCD_SIZE: usize = 22;
+
+/// Offsets inside `data` where a chunk must start, in increasing order.
+/// Empty unless `data` is a tar or zip archive.
+pub fn member_boundaries(data: &[u8]) -> Vec<usize> {
+    let mut cuts = if data.starts_with(ZIP_LOCAL_HEADER) { zip_boundaries(data) } else { tar_boundaries(data) };
+    cuts.sort_unstable();
+    cuts.dedup();
+    cuts.retain(|&cut| cut > 0 && cut < data.len());
+    cuts
+}
+
+// Walks the tar headers until the end-of-archive block or the first header
+// whose checksum doesn't add up (which, at offset 0, means it's not a tar).
+// A member claiming more data than the file holds ends the walk as well.
+fn tar_boundaries(data: &[u8]) -> Vec<usize> {
+    let mut cuts = Vec::new();
+    let mut offset = 0usize;
+    while let Some(header) = offset.checked_add(TAR_BLOCK).and_then(|end| data.get(offset..end)) {
+        if header.iter().all(|&b| b == 0) || !tar_checksum_ok(header) {
+            break;
+        }
+        let data_start = offset + TAR_BLOCK;
+        let member = tar_number(&header[124..136])
+            .and_then(|size| usize::try_from(size).ok())
+            .and_then(|size| Some((data_start.checked_add(size)?, size.checked_next_multiple_of(TAR_BLOCK)?)))
+            .filter(|&(data_end, _)| data_end <= data.len());
+        let Some((data_end, padded)) = member else {
+            break;
+        };
+        cuts.extend([offset, data_start, data_end]);
+        let Some(next) = data_start.checked_add(padded) else {
+            break;
+        };
+        offset = next;
+    }
+    cuts
+}
+
+// The checksum is the byte sum of the header with its own field read as spaces
+fn tar_checksum_ok(header: &[u8]) -> bool {
+    let Some(stored) = tar_number(&header[148..156]) else {
+        return false;
+    };
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum();
+    sum == stored
+}
+
+// Octal, NUL/space padded; or GNU base-256 when the top bit is set
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let bytes = &field[1..];
+        if bytes.len() > 8 && bytes[..bytes.len() - 8].iter().any(|&b| b != 0) {
+            return None;
+        }
+        return Some(bytes.iter().fold(0, |n, &b| (n << 8) | b as u64));
+    }
+    let text = std::str::from_utf8(field).ok()?;
+    let digits = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+// Local headers and data found through the central directory, which (unlike
+// the local headers) always has the compressed sizes
+fn zip_boundaries(data: &[u8]) -> Vec<usize> {
+    let Some(end) = find_end_of_directory(data) else {
+        return Vec::new();
+    };
+    let entries = le16(data, end + 10).unwrap_or(0) as usize;
+    let mut central = le32(data, end + 16).unwrap_or(u32::MAX) as usize;
+    let mut cuts = vec![central];
+
+    for _ in 0..entries {
+        if data.get(central..central + 4) != Some(ZIP_CENTRAL_HEADER.as_slice()) {
+            break;
+        }
+        let (Some(compressed), Some(name_len), Some(extra_len), Some(comment_len), Some(local)) = (
+            le32(data, central + 20),
+            le16(data, central + 28),
+            le16(data, central + 30),
+            le16(data, central + 32),
+            le32(data, central + 42),
+        ) else {
+            break;
+        };
+        central += 46 + name_len as usize + extra_len as usize + comment_len as usize;
+
+        // Zip64 keeps the real values elsewhere; the header is still a member start
+        let local = local as usize;
+        if data.get(local..local + 4) != Some(ZIP_LOCAL_HEADER.as_slice()) {
+            continue;
+        }
+        cuts.push(local);
+        if let (Some(name_len), Some(extra_len)) = (le16(data, local + 26), le16(data, local + 28)) {
+            let start = local + 30 + name_len as usize + extra_len as usize;
+            cuts.push(start);
+            if compressed != u32::MAX {
+                cuts.push(start + compressed as usize);
+            }
+        }
+    }
+    cuts
+}
+
+fn find_end_of_directory(data: &[u8]) -> Option<usize> {
+    let earliest = data.len().saturating_sub(ZIP_EOCD_SIZE + u16::MAX as usize);
+    let last = data.len().checked_sub(ZIP_EOCD_SIZE)?;
+    (earliest..=last).rev().find(|&at| data[at..].starts_with(ZIP_END_OF_DIRECTORY))
+}
+
+fn le16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A zip of stored (uncompressed) members, enough to exercise the parser
+    fn zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let (mut out, mut central) = (Vec::new(), Vec::new());
+        for (name, content) in members {
+            let local = out.len() as u32;
+            let mut fixed = [0u8; 26];
+            fixed[14..18].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            fixed[18..22].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            fixed[22..24].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(ZIP_LOCAL_HEADER);
+            out.extend_from_slice(&fixed);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(content);
+
+            let mut fixed = [0u8; 42];
+            fixed[16..20].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            fixed[20..24].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            fixed[24..26].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            fixed[38..42].copy_from_slice(&local.to_le_bytes());
+            central.extend_from_slice(ZIP_CENTRAL_HEADER);
+            central.extend_from_slice(&fixed);
+            central.extend_from_slice(name.as_bytes());
+        }
+        let directory = out.len() as u32;
+        out.extend_from_slice(&central);
+        let mut end = [0u8; 18];
+        end[4..6].copy_from_slice(&(members.len() as u16).to_le_bytes());
+        end[6..8].copy_from_slice(&(members.len() as u16).to_le_bytes());
+        end[8..12].copy_from_slice(&(central.len() as u32).to_le_bytes());
+        end[12..16].copy_from_slice(&directory.to_le_bytes());
+        out.extend_from_slice(ZIP_END_OF_DIRECTORY);
+        out.extend_from_slice(&end);
+        out
+    }
+
+    #[test]
+    fn test_tar_member_boundaries() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, size) in [("a.txt", 700usize), ("empty", 0), ("b.bin", 1024)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(size as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, vec![7u8; size].as_slice()).unwrap();
+        }
+        let data = builder.into_inner().unwrap();
+
+        // Header, data start and data end of each member: 700 bytes pad to 1024
+        assert_eq!(member_boundaries(&data), vec![512, 1212, 1536, 2048, 2560, 3584]);
+        assert!(member_boundaries(&vec![1u8; 4096]).is_empty());
+        assert!(member_boundaries(&data[..100]).is_empty());
+
+        // A size field near u64::MAX (base-256) or past the end stops the walk;
+        // the members before it keep their cuts
+        let mut huge = tar::Header::new_gnu();
+        huge.as_mut_bytes()[124] = 0x80;
+        huge.as_mut_bytes()[128..136].fill(0xff);
+        huge.set_cksum();
+        let mut crafted = data[..1536].to_vec();
+        crafted.extend_from_slice(huge.as_bytes());
+        crafted.extend_from_slice(&[0u8; 1024]);
+        assert_eq!(member_boundaries(&crafted), vec![512, 1212]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(10_000);
+        header.set_cksum();
+        let mut short = header.as_bytes().to_vec();
+        short.extend_from_slice(&[1u8; 1024]);
+        assert!(member_boundaries(&short).is_empty());
+    }
+
+    #[test]
+    fn test_zip_member_boundaries() {
+        let data = zip(&[("a.txt", b"hello zip"), ("b.txt", b"second")]);
+        // a: header 0, data 35..44; b: header 44, data 79..85; central directory 85
+        assert_eq!(member_boundaries(&data), vec![35, 44, 79, 85]);
+
+        // A damaged directory just means no cuts
+        let mut broken = data.clone();
+        let end = broken.len() - ZIP_EOCD_SIZE;
+        broken[end] = b'X';
+        assert!(member_boundaries(&broken).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+// src/prefetch.rs
+use crate::recipe::{ ChunkOffsets, FileRecipe };
+use std::ops::Range;
+use std::time::Instant;
+
+// Reads in a row at consecutive offsets before we call a stream sequential
+const SEQUENTIAL_THRESHOLD: u32 = 2;
+// Aim to have this much playback time decompressed ahead of the reader
+const LOOKAHEAD_SECS: f64 = 0.25;
+const MIN_WINDOW: usize = 2;
+const MAX_WINDOW: usize = 64;
+// Weight of the newest sample in the read-rate average
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Per-inode read-ahead state. Watches the offsets `read` is called with and,
+/// once the access pattern is sequential, says which chunks to fetch next.
+/// The window grows with the observed read rate: a slow reader only needs a
+/// couple of chunks ahead; a fast `cat` gets up to `MAX_WINDOW`.
+#[derive(Debug)]
+pub struct ReadAhead {
+    next_offset: u64,
+    sequential_reads: u32,
+    last_read: Option<Instant>,
+    // Exponentially weighted bytes/second
+    rate: f64,
+    // Chunks below this index have already been handed out for prefetch
+    prefetched_until: usize,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadAhead {
+    pub fn new() -> Self {
+        ReadAhead {
+            next_offset: 0,
+            sequential_reads: 0,
+            last_read: None,
+            rate: 0.0,
+            prefetched_until: 0,
+        }
+    }
+
+    /// Records a read of `size` bytes at `offset` and returns the chunk
+    /// indexes that should be prefetched now (empty if none). `offsets` is
+    /// built from `recipe`.
+    pub fn on_read(
+        &mut self,
+        recipe: &FileRecipe,
+        offsets: &ChunkOffsets,
+        offset: u64,
+        size: u64,
+        now: Instant
+    ) -> Range<usize> {
+        let Some((current, _)) = offsets.locate(offset) else {
+            return 0..0;
+        };
+
+        if offset == self.next_offset && self.last_read.is_some() {
+            self.sequential_reads += 1;
+            if let Some(last) = self.last_read {
+                // Reads served from cache can land in the same microsecond
+                let elapsed = now.duration_since(last).as_secs_f64().max(1e-4);
+                let sample = (size as f64) / elapsed;
+                self.rate = if self.rate == 0.0 {
+                    sample
+                } else {
+                    RATE_SMOOTHING * sample + (1.0 - RATE_SMOOTHING) * self.rate
+                };
+            }
+        } else {
+            // A seek: start over from the new position
+            self.sequential_reads = 0;
+            self.rate = 0.0;
+            self.prefetched_until = current + 1;
+        }
+        self.next_offset = offset + size;
+        self.last_read = Some(now);
+
+        if self.sequential_reads < SEQUENTIAL_THRESHOLD {
+            return 0..0;
+        }
+        let start = self.prefetched_until.max(current + 1);
+        let end = (current + 1 + self.window(recipe)).min(recipe.chunks.len());
+        if start >= end {
+            return 0..0;
+        }
+        self.prefetched_until = end;
+        start..end
+    }
+
+    /// How many chunks ahead of the reader to keep warm
+    pub fn window(&self, recipe: &FileRecipe) -> usize {
+        if recipe.chunks.is_empty() {
+            return 0;
+        }
+        let avg_chunk = ((recipe.file_size / (recipe.chunks.len() as u64)).max(1)) as f64;
+        let wanted = ((self.rate * LOOKAHEAD_SECS) / avg_chunk).ceil() as usize;
+        wanted.clamp(MIN_WINDOW, MAX_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::{ ChunkRef, FileKind, FileMeta };
+    use std::time::Duration;
+
+    const CHUNK: u64 = 64 * 1024;
+
+    fn recipe(chunks: usize) -> FileRecipe {
+        FileRecipe {
+            file_size: CHUNK * (chunks as u64),
+            chunks: (0..chunks).map(|i| ChunkRef { hash: format!("{:064x}", i), size: CHUNK }).collect(),
+            kind: FileKind::File,
+            meta: FileMeta::default(),
+        }
+    }
+
+    /// Reads 128KB at a time, `interval` apart; returns every prefetch range
+    fn stream(ra: &mut ReadAhead, recipe: &FileRecipe, reads: u64, interval: Duration) -> Vec<Range<usize>> {
+        let start = Instant::now();
+        let offsets = ChunkOffsets::new(recipe);
+        (0..reads)
+            .map(|i| ra.on_read(recipe, &offsets, i * 128 * 1024, 128 * 1024, start + interval * (i as u32)))
+            .filter(|range| !range.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_random_reads_never_prefetch() {
+        let recipe = recipe(100);
+        let offsets = ChunkOffsets::new(&recipe);
+        let mut ra = ReadAhead::new();
+        let now = Instant::now();
+        for offset in [5, 70, 12, 99, 40].map(|c| c * CHUNK) {
+            assert!(ra.on_read(&recipe, &offsets, offset, 4096, now).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sequential_reads_prefetch_each_chunk_once() {
+        let recipe = recipe(100);
+        let mut ra = ReadAhead::new();
+        let ranges = stream(&mut ra, &recipe, 50, Duration::from_millis(10));
+
+        assert!(!ranges.is_empty());
+        // Ranges are contiguous and never overlap
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert_eq!(ranges.last().unwrap().end, 100, "Prefetch should reach the end of the file");
+    }
+
+    #[test]
+    fn test_window_adapts_to_read_rate() {
+        let recipe = recipe(1000);
+
+        // ~1.3 MB/s: 250ms of look-ahead is a few chunks
+        let mut slow = ReadAhead::new();
+        stream(&mut slow, &recipe, 10, Duration::from_millis(100));
+        // ~1.3 GB/s: capped at the maximum
+        let mut fast = ReadAhead::new();
+        stream(&mut fast, &recipe, 10, Duration::from_micros(100));
+
+        assert!(slow.window(&recipe) < 10, "slow window {}", slow.window(&recipe));
+        assert_eq!(fast.window(&recipe), MAX_WINDOW);
+    }
+
+    #[test]
+    fn test_seek_resets_the_stream() {
+        let recipe = recipe(100);
+        let mut ra = ReadAhead::new();
+        stream(&mut ra, &recipe, 5, Duration::from_millis(1));
+        let offsets = ChunkOffsets::new(&recipe);
+        let now = Instant::now();
+        assert!(ra.on_read(&recipe, &offsets, 80 * CHUNK, 4096, now).is_empty());
+        assert!(ra.on_read(&recipe, &offsets, 80 * CHUNK + 4096, 4096, now).is_empty());
+        let range = ra.on_read(&recipe, &offsets, 80 * CHUNK + 8192, 4096, now);
+        assert_eq!(range.start, 81, "Prefetch restarts after the new position");
+    }
+}
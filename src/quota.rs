@@ -0,0 +1,180 @@
+// src/quota.rs
+use crate::recipe::FileRecipe;
+use serde::{ Deserialize, Serialize };
+use sled::transaction::{ ConflictableTransactionResult, TransactionalTree };
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+
+// Quota accounting lives in two trees next to the recipes:
+//   quota_usage: prefix -> Usage
+//   quota_refs:  prefix \0 chunk ID -> (files under prefix using it, stored size)
+// Only prefixes with a quota are tracked, and both trees change in the same
+// transaction as the recipe, so usage never drifts from the metadata.
+
+/// Limits for one subtree; "" is the whole repository
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuotaLimits {
+    /// Sum of file sizes
+    pub logical: Option<u64>,
+    /// On-disk bytes of the distinct chunks the subtree references
+    pub physical: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub logical: u64,
+    pub physical: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaExceeded {
+    pub prefix: String,
+    pub kind: &'static str,
+    pub limit: u64,
+    pub requested: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = if self.prefix.is_empty() { "/" } else { &self.prefix };
+        write!(
+            f,
+            "Quota exceeded on '{}': {} usage would be {} bytes (limit {})",
+            prefix,
+            self.kind,
+            self.requested,
+            self.limit
+        )
+    }
+}
+
+/// Directory semantics: "teams/a" covers "teams/a" and "teams/a/x", not "teams/ab"
+pub fn prefix_matches(prefix: &str, path: &str) -> bool {
+    prefix.is_empty() ||
+        (path.starts_with(prefix) && matches!(path.as_bytes().get(prefix.len()), None | Some(b'/')))
+}
+
+/// "/teams/a/" -> "teams/a"; "/" -> "" (the repository)
+pub fn normalize_prefix(prefix: &str) -> String {
+    prefix.trim_matches('/').to_string()
+}
+
+pub fn ref_key(prefix: &str, hash: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(prefix.len() + 1 + hash.len());
+    key.extend_from_slice(prefix.as_bytes());
+    key.push(0);
+    key.extend_from_slice(hash.as_bytes());
+    key
+}
+
+pub fn decode_usage(bytes: &[u8]) -> Usage {
+    bincode::deserialize(bytes).unwrap_or_default()
+}
+
+/// Moves one prefix's usage from `removed` to `added` (either may be absent),
+/// inside a transaction. `sizes` has the stored size of every chunk in
+/// `added`. Returns the updated usage.
+pub fn apply_change(
+    usage_tree: &TransactionalTree,
+    refs: &TransactionalTree,
+    prefix: &str,
+    removed: Option<&FileRecipe>,
+    added: Option<&FileRecipe>,
+    sizes: &HashMap<String, u64>
+) -> ConflictableTransactionResult<Usage, QuotaExceeded> {
+    let unique = |recipe: Option<&FileRecipe>| -> HashSet<String> {
+        recipe.map_or_else(HashSet::new, |r| r.stored_chunks().map(|c| c.hash.clone()).collect())
+    };
+    let (old_chunks, new_chunks) = (unique(removed), unique(added));
+
+    let before = usage_tree.get(prefix.as_bytes())?.map_or_else(Usage::default, |b| decode_usage(&b));
+    let mut after = before;
+    after.logical = (after.logical + added.map_or(0, |r| r.file_size)).saturating_sub(
+        removed.map_or(0, |r| r.file_size)
+    );
+
+    for hash in old_chunks.difference(&new_chunks) {
+        let key = ref_key(prefix, hash);
+        let Some(entry) = refs.get(&key)? else {
+            continue;
+        };
+        let (count, size): (u64, u64) = bincode::deserialize(&entry).unwrap_or((1, 0));
+        if count <= 1 {
+            refs.remove(key)?;
+            after.physical = after.physical.saturating_sub(size);
+        } else {
+            refs.insert(key, bincode::serialize(&(count - 1, size)).unwrap())?;
+        }
+    }
+    for hash in new_chunks.difference(&old_chunks) {
+        let key = ref_key(prefix, hash);
+        let (count, size) = match refs.get(&key)? {
+            Some(entry) => bincode::deserialize::<(u64, u64)>(&entry).unwrap_or((0, 0)),
+            None => {
+                let size = sizes.get(hash).copied().unwrap_or(0);
+                after.physical += size;
+                (0, size)
+            }
+        };
+        refs.insert(key, bincode::serialize(&(count + 1, size)).unwrap())?;
+    }
+
+    usage_tree.insert(prefix.as_bytes(), bincode::serialize(&after).unwrap())?;
+    Ok(after)
+}
+
+/// Refuses a change that leaves usage above a limit *and* higher than it
+/// was. Shrinking is always allowed, even while over quota.
+pub fn check_limits(
+    prefix: &str,
+    limits: &QuotaLimits,
+    before: Usage,
+    after: Usage
+) -> Result<(), QuotaExceeded> {
+    let checks = [
+        ("logical", limits.logical, before.logical, after.logical),
+        ("physical", limits.physical, before.physical, after.physical),
+    ];
+    for (kind, limit, before, after) in checks {
+        if let Some(limit) = limit
+            && after > limit
+            && after > before
+        {
+            return Err(QuotaExceeded { prefix: prefix.to_string(), kind, limit, requested: after });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_matching() {
+        assert!(prefix_matches("", "anything/at/all"));
+        assert!(prefix_matches("teams/a", "teams/a"));
+        assert!(prefix_matches("teams/a", "teams/a/report.pdf"));
+        assert!(!prefix_matches("teams/a", "teams/ab/report.pdf"));
+        assert!(!prefix_matches("teams/a", "teams"));
+        assert_eq!(normalize_prefix("/teams/a/"), "teams/a");
+        assert_eq!(normalize_prefix("/"), "");
+    }
+
+    #[test]
+    fn test_limits_only_block_growth() {
+        let limits = QuotaLimits { logical: Some(100), physical: None };
+        let usage = |logical| Usage { logical, physical: 0 };
+        assert!(check_limits("a", &limits, usage(50), usage(100)).is_ok());
+        assert_eq!(check_limits("a", &limits, usage(50), usage(101)).unwrap_err().kind, "logical");
+        // Already over: shrinking passes, growing doesn't
+        assert!(check_limits("a", &limits, usage(150), usage(120)).is_ok());
+        assert!(check_limits("a", &limits, usage(150), usage(151)).is_err());
+    }
+
+    #[test]
+    fn test_message_names_the_subtree() {
+        let err = QuotaExceeded { prefix: String::new(), kind: "physical", limit: 10, requested: 12 };
+        assert_eq!(err.to_string(), "Quota exceeded on '/': physical usage would be 12 bytes (limit 10)");
+    }
+}
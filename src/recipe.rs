@@ -0,0 +1,323 @@
+// src/recipe.rs
+use serde::{ Deserialize, Serialize };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+// Recipes are stored as [b"BFR"][version: u8][bincode(FileRecipe)].
+// A chunk with an empty hash is a hole: that many zero bytes, nothing stored.
+// Version 1 recipes predate the header and are plain bincode without chunk
+// sizes. A v1 recipe would need a file size whose low bytes spell "BFR\x0N"
+// *and* still decode as that version to be misread, which doesn't happen in
+// practice. Version 2 recipes are v3 without `meta`.
+const RECIPE_MAGIC: &[u8; 3] = b"BFR";
+const RECIPE_VERSION: u8 = 3;
+const RECIPE_VERSION_2: u8 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FileKind {
+    File,
+    Directory,
+    /// The link target is the content
+    Symlink,
+}
+
+/// Attributes kept from the host filesystem on import. Zero means unknown
+/// (recipes written before there was metadata, or by a plain write).
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct FileMeta {
+    /// Permission bits, st_mode & 0o7777
+    pub mode: u32,
+    /// Modification time since the epoch
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+impl FileMeta {
+    /// Mode and mtime of a file on the host (lstat(2) or stat(2) result)
+    pub fn from_fs(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        FileMeta {
+            mode: metadata.mode() & 0o7777,
+            mtime_secs: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec() as u32,
+        }
+    }
+
+    /// Unknown mode, modified now
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        FileMeta { mode: 0, mtime_secs: since_epoch.as_secs() as i64, mtime_nanos: since_epoch.subsec_nanos() }
+    }
+
+    /// The same mode, modified now
+    pub fn touched(&self) -> Self {
+        FileMeta { mode: self.mode, ..FileMeta::now() }
+    }
+
+    pub fn mtime(&self) -> Option<SystemTime> {
+        if (self.mtime_secs, self.mtime_nanos) == (0, 0) {
+            return None;
+        }
+        let offset = Duration::new(self.mtime_secs.unsigned_abs(), self.mtime_nanos);
+        if self.mtime_secs >= 0 { UNIX_EPOCH.checked_add(offset) } else { UNIX_EPOCH.checked_sub(offset) }
+    }
+}
+
+/// One stored chunk of a file, in file order
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChunkRef {
+    pub hash: String,
+    /// Decompressed length, so byte ranges can be served without reading
+    /// every preceding chunk
+    pub size: u64,
+}
+
+impl ChunkRef {
+    /// `size` zero bytes that take no space in the store
+    pub fn hole(size: u64) -> Self {
+        ChunkRef { hash: String::new(), size }
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.hash.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRecipe {
+    pub file_size: u64,
+    pub chunks: Vec<ChunkRef>, // Chunks in file order
+    pub kind: FileKind,
+    pub meta: FileMeta,
+}
+
+// Version 2: no metadata
+#[derive(Deserialize)]
+struct RecipeV2 {
+    file_size: u64,
+    chunks: Vec<ChunkRef>,
+    kind: FileKind,
+}
+
+// The layout written before recipes had a version header
+#[derive(Deserialize)]
+struct RecipeV1 {
+    file_size: u64,
+    chunks: Vec<String>,
+    kind: FileKind,
+}
+
+impl FileRecipe {
+    pub fn directory() -> Self {
+        FileRecipe { file_size: 0, chunks: Vec::new(), kind: FileKind::Directory, meta: FileMeta::default() }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut encoded = Vec::with_capacity(64 + self.chunks.len() * 80);
+        encoded.extend_from_slice(RECIPE_MAGIC);
+        encoded.push(RECIPE_VERSION);
+        bincode
+            ::serialize_into(&mut encoded, self)
+            .map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(encoded)
+    }
+
+    /// Decodes any recipe version. The flag is true for v1 recipes, whose
+    /// chunk sizes are unknown (left as 0) until the caller fills them in.
+    pub fn decode(bytes: &[u8]) -> Result<(FileRecipe, bool), String> {
+        if let Some(body) = bytes.strip_prefix(RECIPE_MAGIC)
+            && body.first() == Some(&RECIPE_VERSION)
+            && let Ok(recipe) = bincode::deserialize::<FileRecipe>(&body[1..])
+        {
+            return Ok((recipe, false));
+        }
+        if let Some(body) = bytes.strip_prefix(RECIPE_MAGIC)
+            && body.first() == Some(&RECIPE_VERSION_2)
+            && let Ok(old) = bincode::deserialize::<RecipeV2>(&body[1..])
+        {
+            let recipe = FileRecipe { file_size: old.file_size, chunks: old.chunks, kind: old.kind, meta: FileMeta::default() };
+            return Ok((recipe, false));
+        }
+
+        let legacy: RecipeV1 = bincode
+            ::deserialize(bytes)
+            .map_err(|e| format!("Deserialization error: {}", e))?;
+        let chunks = legacy.chunks
+            .into_iter()
+            .map(|hash| ChunkRef { hash, size: 0 })
+            .collect();
+        let meta = FileMeta::default();
+        Ok((FileRecipe { file_size: legacy.file_size, chunks, kind: legacy.kind, meta }, true))
+    }
+
+    /// The chunks that exist in the store (everything but holes)
+    pub fn stored_chunks(&self) -> impl Iterator<Item = &ChunkRef> {
+        self.chunks.iter().filter(|chunk| !chunk.is_hole())
+    }
+
+    /// lseek(SEEK_DATA / SEEK_HOLE): the first offset at or after `offset`
+    /// that is data (or a hole). The end of the file counts as a hole; `None`
+    /// means there is no such offset (ENXIO).
+    pub fn seek(&self, offset: u64, hole: bool) -> Option<u64> {
+        if offset >= self.file_size {
+            return None;
+        }
+        let mut start = 0;
+        for chunk in &self.chunks {
+            let end = start + chunk.size;
+            if end > offset && chunk.is_hole() == hole {
+                return Some(start.max(offset));
+            }
+            start = end;
+        }
+        if hole { Some(self.file_size) } else { None }
+    }
+
+    /// Finds the chunk holding byte `offset`: (chunk index, offset where it
+    /// starts). Walks the chunk list; see `ChunkOffsets` for repeated lookups.
+    pub fn locate(&self, offset: u64) -> Option<(usize, u64)> {
+        let mut start = 0;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            if offset < start + chunk.size {
+                return Some((index, start));
+            }
+            start += chunk.size;
+        }
+        None
+    }
+}
+
+/// Where each chunk of a recipe ends, so the chunk holding an offset is a
+/// binary search away. Worth building once for a recipe that is read over
+/// and over, such as an open file's.
+#[derive(Debug, Clone)]
+pub struct ChunkOffsets {
+    ends: Vec<u64>,
+}
+
+impl ChunkOffsets {
+    pub fn new(recipe: &FileRecipe) -> Self {
+        let mut end = 0;
+        let ends = recipe.chunks
+            .iter()
+            .map(|chunk| {
+                end += chunk.size;
+                end
+            })
+            .collect();
+        ChunkOffsets { ends }
+    }
+
+    /// Same answer as `FileRecipe::locate`
+    pub fn locate(&self, offset: u64) -> Option<(usize, u64)> {
+        let index = self.ends.partition_point(|&end| end <= offset);
+        let start = index.checked_sub(1).map_or(0, |previous| self.ends[previous]);
+        (index < self.ends.len()).then_some((index, start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> FileRecipe {
+        FileRecipe {
+            file_size: 250,
+            chunks: vec![
+                ChunkRef { hash: "aa".repeat(32), size: 100 },
+                ChunkRef { hash: "bb".repeat(32), size: 100 },
+                ChunkRef { hash: "cc".repeat(32), size: 50 }
+            ],
+            kind: FileKind::File,
+            meta: FileMeta::default(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (decoded, legacy) = FileRecipe::decode(&sample().encode().unwrap()).unwrap();
+        assert!(!legacy);
+        assert_eq!(decoded.chunks, sample().chunks);
+        assert_eq!(decoded.file_size, 250);
+    }
+
+    #[test]
+    fn test_decodes_v1_recipes() {
+        #[derive(Serialize)]
+        struct OldRecipe {
+            file_size: u64,
+            chunks: Vec<String>,
+            kind: FileKind,
+        }
+        let old = OldRecipe { file_size: 11, chunks: vec!["ab".repeat(32)], kind: FileKind::File };
+        let (decoded, legacy) = FileRecipe::decode(&bincode::serialize(&old).unwrap()).unwrap();
+        assert!(legacy);
+        assert_eq!(decoded.file_size, 11);
+        assert_eq!(decoded.chunks[0].hash, "ab".repeat(32));
+    }
+
+    #[test]
+    fn test_decodes_v2_recipes_without_meta() {
+        #[derive(Serialize)]
+        struct V2 {
+            file_size: u64,
+            chunks: Vec<ChunkRef>,
+            kind: FileKind,
+        }
+        let old = V2 { file_size: 250, chunks: sample().chunks, kind: FileKind::File };
+        let mut bytes = RECIPE_MAGIC.to_vec();
+        bytes.push(RECIPE_VERSION_2);
+        bytes.extend(bincode::serialize(&old).unwrap());
+        let (decoded, legacy) = FileRecipe::decode(&bytes).unwrap();
+        assert!(!legacy);
+        assert_eq!((decoded.chunks, decoded.meta), (sample().chunks, FileMeta::default()));
+
+        // New recipes keep their metadata
+        let meta = FileMeta { mode: 0o755, mtime_secs: 1_700_000_000, mtime_nanos: 5 };
+        let (decoded, _) = FileRecipe::decode(&FileRecipe { meta, ..sample() }.encode().unwrap()).unwrap();
+        assert_eq!(decoded.meta, meta);
+        assert_eq!(FileMeta::default().mtime(), None);
+    }
+
+    #[test]
+    fn test_seek_data_and_hole() {
+        let recipe = FileRecipe {
+            file_size: 300,
+            chunks: vec![
+                ChunkRef { hash: "aa".repeat(32), size: 100 },
+                ChunkRef::hole(100),
+                ChunkRef { hash: "bb".repeat(32), size: 100 }
+            ],
+            kind: FileKind::File,
+            meta: FileMeta::default(),
+        };
+        assert_eq!(recipe.seek(0, false), Some(0));
+        assert_eq!(recipe.seek(0, true), Some(100));
+        assert_eq!(recipe.seek(150, false), Some(200));
+        assert_eq!(recipe.seek(150, true), Some(150));
+        // Past the last hole, the end of the file is the next one
+        assert_eq!(recipe.seek(250, true), Some(300));
+        assert_eq!(recipe.seek(300, false), None);
+        assert_eq!(recipe.stored_chunks().count(), 2);
+    }
+
+    #[test]
+    fn test_locate() {
+        let recipe = sample();
+        assert_eq!(recipe.locate(0), Some((0, 0)));
+        assert_eq!(recipe.locate(99), Some((0, 0)));
+        assert_eq!(recipe.locate(100), Some((1, 100)));
+        assert_eq!(recipe.locate(249), Some((2, 200)));
+        assert_eq!(recipe.locate(250), None);
+
+        // The binary search agrees everywhere, empty chunks included
+        let mut chunks = recipe.chunks.clone();
+        chunks.insert(1, ChunkRef::hole(0));
+        let recipe = FileRecipe { chunks, ..recipe };
+        let offsets = ChunkOffsets::new(&recipe);
+        for offset in 0..=260 {
+            assert_eq!(offsets.locate(offset), recipe.locate(offset), "{}", offset);
+        }
+        assert_eq!(offsets.locate(100), Some((2, 100)));
+        assert_eq!(ChunkOffsets::new(&FileRecipe::directory()).locate(0), None);
+    }
+}
@@ -0,0 +1,191 @@
+// src/repo.rs
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+// `init` writes this into the repository root. Every other command refuses
+// a directory without it, so a mistyped path never grows a new store.
+pub const MARKER_FILE: &str = "betterfs-repo.toml";
+const FORMAT_VERSION: u32 = 1;
+
+pub const REPO_ENV: &str = "BETTERFS_REPO";
+// Used when no flag, variable or config default names a repository
+const DEFAULT_REPO: &str = "./my_storage";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Marker {
+    format: u32,
+}
+
+/// The CLI config file (see `config_path`). Names repositories so that
+/// `--repo photos` works from anywhere:
+///
+/// ```toml
+/// default = "photos"
+///
+/// [repos.photos]
+/// path = "~/betterfs/photos"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Repository used when neither `--repo` nor BETTERFS_REPO is given
+    pub default: Option<String>,
+    #[serde(default)]
+    pub repos: BTreeMap<String, RepoEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoEntry {
+    pub path: PathBuf,
+}
+
+impl Config {
+    /// Reads the config file; a missing one is the same as an empty one
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| format!("Bad config file {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Could not read config file {}: {}", path.display(), e)),
+        }
+    }
+
+    /// The repository to use: `--repo`, then BETTERFS_REPO, then the config
+    /// default, then ./my_storage. Each may be a configured name or a path.
+    pub fn resolve(&self, flag: Option<&str>, env: Option<&str>) -> Result<PathBuf, String> {
+        if let Some(repo) = flag.or(env) {
+            return Ok(self.lookup(repo));
+        }
+        match &self.default {
+            Some(name) => self.repos
+                .get(name)
+                .map(|entry| expand_home(&entry.path))
+                .ok_or_else(|| format!("Config default '{}' is not one of the [repos]", name)),
+            None => Ok(PathBuf::from(DEFAULT_REPO)),
+        }
+    }
+
+    fn lookup(&self, name_or_path: &str) -> PathBuf {
+        match self.repos.get(name_or_path) {
+            Some(entry) => expand_home(&entry.path),
+            None => expand_home(Path::new(name_or_path)),
+        }
+    }
+}
+
+/// $XDG_CONFIG_HOME/better-fs/config.toml, normally ~/.config/better-fs/config.toml
+pub fn config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("better-fs").join("config.toml"))
+}
+
+// "~/x" -> "$HOME/x"; anything else is left alone
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// `better-fs init`: marks `path` as a repository, creating it if needed.
+/// A store from before markers existed is adopted as it is; any other
+/// non-empty directory is refused. Returns whether a store was adopted.
+pub fn init(path: &Path) -> Result<bool, String> {
+    if path.join(MARKER_FILE).exists() {
+        return Err(format!("{} is already a BetterFS repository", path.display()));
+    }
+    let adopted = path.join("metadata_db").is_dir();
+    let empty = match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true, // Doesn't exist yet
+    };
+    if !adopted && !empty {
+        return Err(format!("{} is not empty; refusing to create a repository in it", path.display()));
+    }
+
+    fs::create_dir_all(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+    let marker = toml::to_string(&Marker { format: FORMAT_VERSION }).map_err(|e| e.to_string())?;
+    fs::write(path.join(MARKER_FILE), format!("# BetterFS repository. Do not delete.\n{}", marker))
+        .map_err(|e| format!("Could not write {}: {}", MARKER_FILE, e))?;
+    Ok(adopted)
+}
+
+/// Fails unless `path` is a repository this version can open
+pub fn check(path: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(path.join(MARKER_FILE)).map_err(|_| {
+        format!("{} is not a BetterFS repository (create one with `better-fs init`)", path.display())
+    })?;
+    let marker: Marker = toml::from_str(&text).map_err(|e| format!("Bad {}: {}", MARKER_FILE, e))?;
+    if marker.format > FORMAT_VERSION {
+        return Err(format!(
+            "{} has repository format {}; this better-fs only understands up to {}",
+            path.display(),
+            marker.format,
+            FORMAT_VERSION
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::reset;
+
+    #[test]
+    fn test_resolve_order_and_names() {
+        let config: Config = toml::from_str(
+            r#"
+            default = "photos"
+            [repos.photos]
+            path = "/srv/photos"
+            [repos.work]
+            path = "/srv/work"
+            "#
+        ).unwrap();
+
+        // Flag beats env var beats config default; names map to their path
+        assert_eq!(config.resolve(Some("work"), Some("photos")).unwrap(), PathBuf::from("/srv/work"));
+        assert_eq!(config.resolve(None, Some("/tmp/adhoc")).unwrap(), PathBuf::from("/tmp/adhoc"));
+        assert_eq!(config.resolve(None, None).unwrap(), PathBuf::from("/srv/photos"));
+        assert_eq!(Config::default().resolve(None, None).unwrap(), PathBuf::from(DEFAULT_REPO));
+
+        let broken: Config = toml::from_str("default = \"missing\"").unwrap();
+        assert!(broken.resolve(None, None).is_err());
+        assert!(toml::from_str::<Config>("[repos.x]\npth = \"/typo\"").is_err());
+    }
+
+    #[test]
+    fn test_init_and_check() {
+        reset("./test_repo_init");
+        let root = Path::new("./test_repo_init");
+
+        // 1. A fresh directory: refused until init, which can't run twice
+        let fresh = root.join("fresh");
+        assert!(check(&fresh).is_err());
+        assert!(!init(&fresh).unwrap());
+        check(&fresh).unwrap();
+        assert!(init(&fresh).is_err());
+
+        // 2. A store from before markers is adopted; a random directory is not
+        let legacy = root.join("legacy");
+        fs::create_dir_all(legacy.join("metadata_db")).unwrap();
+        assert!(init(&legacy).unwrap());
+        let random = root.join("random");
+        fs::create_dir_all(&random).unwrap();
+        fs::write(random.join("notes.txt"), b"hi").unwrap();
+        assert!(init(&random).is_err());
+        assert!(!random.join(MARKER_FILE).exists());
+
+        // 3. Newer formats are refused rather than misread
+        fs::write(fresh.join(MARKER_FILE), "format = 99\n").unwrap();
+        assert!(check(&fresh).unwrap_err().contains("format 99"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+// src/stats.rs
+use crate::quota::{ normalize_prefix, prefix_matches };
+use crate::recipe::{ FileKind, FileRecipe };
+use serde::Serialize;
+use std::collections::{ BTreeMap, HashMap, HashSet };
+
+/// Repository-wide dedup and compression figures (`better-fs stats`)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepoStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Sum of file sizes, as applications see them
+    pub logical_bytes: u64,
+    /// Part of logical_bytes that sits in holes and takes no space at all
+    pub hole_bytes: u64,
+    /// Chunk references across all recipes, repeats included
+    pub chunk_refs: u64,
+    pub unique_chunks: u64,
+    /// Decompressed size of each distinct chunk, counted once
+    pub unique_bytes: u64,
+    /// What those chunks take on disk after compression
+    pub stored_bytes: u64,
+    /// Logical bytes outside holes / unique: how much dedup saves
+    pub dedup_ratio: f64,
+    /// unique / stored: how much compression saves on top
+    pub compression_ratio: f64,
+    /// Distinct chunks by decompressed size, in power-of-two buckets
+    pub histogram: Vec<SizeBucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeBucket {
+    /// Inclusive lower bound in bytes
+    pub min_size: u64,
+    /// Exclusive upper bound in bytes
+    pub max_size: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+}
+
+/// Space attributed to a file or directory (`better-fs du`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DuEntry {
+    pub path: String,
+    pub files: u64,
+    pub logical_bytes: u64,
+    /// Stored bytes of chunks only this entry references: freed if it's deleted
+    pub exclusive_bytes: u64,
+    /// Stored bytes of chunks it references that other files use too
+    pub shared_bytes: u64,
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 { 1.0 } else { (numerator as f64) / (denominator as f64) }
+}
+
+/// Distinct chunks of one recipe
+fn unique_hashes(recipe: &FileRecipe) -> HashSet<&str> {
+    recipe.stored_chunks().map(|chunk| chunk.hash.as_str()).collect()
+}
+
+/// `stored` has the on-disk size of every chunk the recipes reference
+pub fn repo_stats(files: &[(String, FileRecipe)], stored: &HashMap<String, u64>) -> RepoStats {
+    let mut stats = RepoStats::default();
+    let mut sizes: HashMap<&str, u64> = HashMap::new();
+
+    for (_, recipe) in files {
+        match recipe.kind {
+            FileKind::Directory => {
+                stats.directories += 1;
+                continue;
+            }
+            FileKind::File => stats.files += 1,
+            FileKind::Symlink => stats.symlinks += 1,
+        }
+        stats.logical_bytes += recipe.file_size;
+        for chunk in &recipe.chunks {
+            if chunk.is_hole() {
+                stats.hole_bytes += chunk.size;
+                continue;
+            }
+            stats.chunk_refs += 1;
+            sizes.insert(&chunk.hash, chunk.size);
+        }
+    }
+
+    let mut buckets: BTreeMap<u32, SizeBucket> = BTreeMap::new();
+    for (hash, size) in sizes {
+        stats.unique_chunks += 1;
+        stats.unique_bytes += size;
+        stats.stored_bytes += stored.get(hash).copied().unwrap_or(0);
+
+        // Bucket n holds sizes in [2^n, 2^(n+1)); empty chunks go in bucket 0
+        let bucket = size.max(1).ilog2();
+        let entry = buckets.entry(bucket).or_insert_with(|| SizeBucket {
+            min_size: if bucket == 0 { 0 } else { 1 << bucket },
+            max_size: 1 << (bucket + 1),
+            chunks: 0,
+            bytes: 0,
+        });
+        entry.chunks += 1;
+        entry.bytes += size;
+    }
+    stats.histogram = buckets.into_values().collect();
+    // Holes cost nothing without dedup either, so they don't count as savings
+    stats.dedup_ratio = ratio(stats.logical_bytes - stats.hole_bytes, stats.unique_bytes);
+    stats.compression_ratio = ratio(stats.unique_bytes, stats.stored_bytes);
+    stats
+}
+
+/// Usage of `root` (a file or directory; "" is everything): one entry per
+/// immediate child, sorted by path, then the total for `root` itself.
+pub fn disk_usage(files: &[(String, FileRecipe)], stored: &HashMap<String, u64>, root: &str) -> Vec<DuEntry> {
+    let root = normalize_prefix(root);
+
+    // 1. How many files reference each chunk, repository-wide
+    let mut refs: HashMap<&str, u64> = HashMap::new();
+    for (_, recipe) in files {
+        for hash in unique_hashes(recipe) {
+            *refs.entry(hash).or_default() += 1;
+        }
+    }
+
+    // 2. Group the files under root by their first path component below it
+    let mut groups: BTreeMap<String, Vec<&FileRecipe>> = BTreeMap::new();
+    let mut everything = Vec::new();
+    for (path, recipe) in files {
+        if !prefix_matches(&root, path) {
+            continue;
+        }
+        let rest = path[root.len()..].trim_start_matches('/');
+        let child = match rest.split('/').next() {
+            Some(name) if !name.is_empty() => {
+                if root.is_empty() { name.to_string() } else { format!("{}/{}", root, name) }
+            }
+            // root itself: a directory record adds nothing of its own
+            _ if recipe.kind == FileKind::Directory => continue,
+            _ => path.clone(), // root is this file
+        };
+        groups.entry(child).or_default().push(recipe);
+        everything.push(recipe);
+    }
+    if everything.is_empty() {
+        return Vec::new();
+    }
+
+    // 3. A chunk is exclusive to a group if every reference comes from inside it
+    let measure = |path: String, recipes: &[&FileRecipe]| {
+        let mut entry = DuEntry { path, ..DuEntry::default() };
+        let mut local: HashMap<&str, u64> = HashMap::new();
+        for recipe in recipes {
+            if recipe.kind == FileKind::File {
+                entry.files += 1;
+                entry.logical_bytes += recipe.file_size;
+            }
+            for hash in unique_hashes(recipe) {
+                *local.entry(hash).or_default() += 1;
+            }
+        }
+        for (hash, count) in local {
+            let size = stored.get(hash).copied().unwrap_or(0);
+            if count == refs[hash] {
+                entry.exclusive_bytes += size;
+            } else {
+                entry.shared_bytes += size;
+            }
+        }
+        entry
+    };
+
+    let mut entries: Vec<DuEntry> = Vec::new();
+    let is_single_file = groups.len() == 1 && groups.contains_key(&root);
+    if !is_single_file {
+        for (child, recipes) in &groups {
+            entries.push(measure(child.clone(), recipes));
+        }
+    }
+    entries.push(measure(if root.is_empty() { "/".to_string() } else { root }, &everything));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::{ ChunkRef, FileMeta };
+
+    fn file(path: &str, chunks: &[(&str, u64)]) -> (String, FileRecipe) {
+        let chunks: Vec<ChunkRef> = chunks
+            .iter()
+            .map(|(hash, size)| ChunkRef { hash: hash.to_string(), size: *size })
+            .collect();
+        let file_size = chunks.iter().map(|c| c.size).sum();
+        (path.to_string(), FileRecipe { file_size, chunks, kind: FileKind::File, meta: FileMeta::default() })
+    }
+
+    fn sample() -> (Vec<(String, FileRecipe)>, HashMap<String, u64>) {
+        let files = vec![
+            ("docs".to_string(), FileRecipe::directory()),
+            file("docs/a.txt", &[("x", 4096), ("y", 3000)]),
+            file("docs/b.txt", &[("x", 4096), ("x", 4096)]),
+            file("media/c.bin", &[("y", 3000), ("z", 40_000)]),
+            // All hole: logical size, no chunks
+            file("media/sparse.img", &[("", 1 << 20)])
+        ];
+        // Stored sizes are half the decompressed ones
+        let stored = [("x", 2048), ("y", 1500), ("z", 20_000)]
+            .iter()
+            .map(|(hash, size)| (hash.to_string(), *size))
+            .collect();
+        (files, stored)
+    }
+
+    #[test]
+    fn test_repo_stats() {
+        let (files, stored) = sample();
+        let stats = repo_stats(&files, &stored);
+        assert_eq!((stats.files, stats.directories), (4, 1));
+        assert_eq!(stats.logical_bytes, 7096 + 8192 + 43_000 + (1 << 20));
+        assert_eq!(stats.hole_bytes, 1 << 20);
+        assert_eq!(stats.chunk_refs, 6);
+        assert_eq!(stats.unique_chunks, 3);
+        assert_eq!(stats.unique_bytes, 47_096);
+        assert_eq!(stats.stored_bytes, 23_548);
+        assert!((stats.compression_ratio - 2.0).abs() < 1e-9);
+        // The sparse file's hole stays out of it: 58_288 bytes of data over 47_096 unique
+        assert_eq!(stats.dedup_ratio, 58_288.0 / 47_096.0);
+
+        // 2048..4096 holds y, 4096..8192 holds x, 32768..65536 holds z
+        let buckets: Vec<(u64, u64)> = stats.histogram.iter().map(|b| (b.min_size, b.chunks)).collect();
+        assert_eq!(buckets, vec![(2048, 1), (4096, 1), (32_768, 1)]);
+    }
+
+    #[test]
+    fn test_disk_usage_splits_exclusive_and_shared() {
+        let (files, stored) = sample();
+        let entries = disk_usage(&files, &stored, "/");
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["docs", "media", "/"]);
+
+        // x is docs-only; y is shared with media
+        assert_eq!(entries[0].exclusive_bytes, 2048);
+        assert_eq!(entries[0].shared_bytes, 1500);
+        assert_eq!(entries[1].exclusive_bytes, 20_000);
+        assert_eq!(entries[1].shared_bytes, 1500);
+        // Everything is exclusive to the whole repository
+        assert_eq!(entries[2].exclusive_bytes, 23_548);
+        assert_eq!(entries[2].files, 4);
+
+        // Inside docs each file shares x with the other, and a.txt shares y with media
+        let docs = disk_usage(&files, &stored, "docs");
+        assert_eq!(docs[0].path, "docs/a.txt");
+        assert_eq!((docs[0].exclusive_bytes, docs[0].shared_bytes), (0, 3548));
+        assert_eq!((docs[1].exclusive_bytes, docs[1].shared_bytes), (0, 2048));
+
+        // A single file reports just itself
+        let single = disk_usage(&files, &stored, "media/c.bin");
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].exclusive_bytes, 20_000);
+        assert!(disk_usage(&files, &stored, "nowhere").is_empty());
+    }
+}
@@ -1,11 +1,159 @@
 // src/storage.rs
+use crate::chunk_cache::{ CacheStats, ChunkCache };
+use crate::compression::{ self, CompressionPolicy, Dictionary };
 use sha2::{ Sha256, Digest };
+use serde::{ Deserialize, Serialize };
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
 use std::fs::{ self, File };
 use std::io::{ Read, Write };
 use std::path::PathBuf;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, SystemTime };
+
+// Makes temp file names unique within this process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+const INJECTED_CRASH: &str = "injected crash";
+
+/// Places where tests can simulate a crash (see tests/crash_safety.rs)
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// Half of a chunk's bytes reached its temp file
+    ChunkHalfWritten,
+    /// The temp file is complete and synced but not yet linked into place
+    ChunkBeforeRename,
+    /// All chunks are durable; the recipe hasn't been inserted
+    BeforeRecipeCommit,
+}
+
+/// The hash function used to address chunks.
+///
+/// Chunk IDs carry their algorithm so stores that mix both (e.g. after
+/// switching a repository to BLAKE3) stay readable, verifiable and collectable.
+/// SHA-256 IDs are bare hex for compatibility with stores written before the
+/// setting existed; every other algorithm prefixes its name: `blake3-<hex>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 2] = [HashAlgorithm::Sha256, HashAlgorithm::Blake3];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Hex digest of `data`
+    pub fn digest(&self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(data);
+                hex::encode(hasher.finalize())
+            }
+            HashAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+
+    /// Builds the chunk ID for a digest produced by this algorithm
+    pub fn chunk_id(&self, digest: &str) -> String {
+        match self {
+            HashAlgorithm::Sha256 => digest.to_string(),
+            _ => format!("{}-{}", self.name(), digest),
+        }
+    }
+
+    /// Splits a chunk ID into its algorithm and hex digest
+    pub fn from_chunk_id(id: &str) -> (HashAlgorithm, &str) {
+        for alg in HashAlgorithm::ALL {
+            if alg == HashAlgorithm::Sha256 {
+                continue;
+            }
+            if let Some(digest) = id.strip_prefix(alg.name()).and_then(|r| r.strip_prefix('-')) {
+                return (alg, digest);
+            }
+        }
+        (HashAlgorithm::Sha256, id)
+    }
+
+    // Chunk files of non-default algorithms get an extension, so the
+    // two-level "cas/ab/cdef..." layout is shared by every algorithm.
+    fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            HashAlgorithm::Sha256 => None,
+            _ => Some(self.name()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|alg| alg.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown hash algorithm '{}' (expected sha256 or blake3)", s))
+    }
+}
+
+/// Chunks that compress by less than this are stored raw
+pub const DEFAULT_MIN_SAVINGS_PERCENT: u8 = 10;
+
+/// Default budget for the decompressed-chunk cache (64 MiB)
+pub const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// What `statvfs` reports for the filesystem under the repository
+#[derive(Debug, Clone, Copy)]
+pub struct DiskSpace {
+    pub total_bytes: u64,
+    /// Free space usable by unprivileged writers
+    pub available_bytes: u64,
+    pub free_inodes: u64,
+}
 
 pub struct Storage {
     root_dir: PathBuf,
+    hash_algorithm: HashAlgorithm,
+    compression: CompressionPolicy,
+    min_savings_percent: u8,
+    // Dictionary applied to new zstd chunks (see `better-fs train-dict`)
+    active_dictionary: Option<Arc<Dictionary>>,
+    // Dictionaries loaded so far for decoding, keyed by ID
+    dictionaries: Mutex<HashMap<u32, Arc<Dictionary>>>,
+    // Directories with new links that `sync` still has to make durable
+    dirty_dirs: Mutex<HashSet<PathBuf>>,
+    // Decompressed chunks, so hot and sequentially re-read chunks skip the disk
+    cache: ChunkCache,
+    // On-disk bytes of all chunks; counted on first use, then kept up to date
+    stored_bytes: Mutex<Option<u64>>,
+    // Armed crash point and how many hits to let through first
+    #[cfg(test)]
+    crash: Mutex<Option<(CrashPoint, usize)>>,
+    // Set once the simulated crash fires: the "process" is gone
+    #[cfg(test)]
+    crashed: std::sync::atomic::AtomicBool,
+    // Held by chunk writes while a crash is armed (see `crash_gate`)
+    #[cfg(test)]
+    crash_serial: Mutex<()>,
 }
 
 impl Storage {
@@ -13,43 +161,287 @@ impl Storage {
         let root_dir = path.into();
         // Ensure the storage directory exists (e.g., /tmp/betterfs_data)
         fs::create_dir_all(&root_dir).unwrap();
-        Storage { root_dir }
+        Storage {
+            root_dir,
+            hash_algorithm: HashAlgorithm::default(),
+            compression: CompressionPolicy::default(),
+            min_savings_percent: DEFAULT_MIN_SAVINGS_PERCENT,
+            active_dictionary: None,
+            dictionaries: Mutex::new(HashMap::new()),
+            dirty_dirs: Mutex::new(HashSet::new()),
+            cache: ChunkCache::new(DEFAULT_CACHE_BYTES),
+            stored_bytes: Mutex::new(None),
+            #[cfg(test)]
+            crash: Mutex::new(None),
+            #[cfg(test)]
+            crashed: std::sync::atomic::AtomicBool::new(false),
+            #[cfg(test)]
+            crash_serial: Mutex::new(()),
+        }
+    }
+
+    /// Selects the algorithm used to address newly written chunks.
+    /// Existing chunks keep the algorithm encoded in their ID.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Sets the compression used by `write_chunk` when no policy is given
+    pub fn set_compression(&mut self, policy: CompressionPolicy, min_savings_percent: u8) {
+        self.compression = policy;
+        self.min_savings_percent = min_savings_percent;
+    }
+
+    pub fn compression(&self) -> CompressionPolicy {
+        self.compression
+    }
+
+    pub fn min_savings_percent(&self) -> u8 {
+        self.min_savings_percent
+    }
+
+    /// Saves a trained dictionary as "dicts/<id>" and returns its ID. The same
+    /// bytes always get the same ID; a different dictionary already holding
+    /// that ID (it's only 32 bits of the hash) moves this one to the next free ID.
+    pub fn store_dictionary(&self, data: Vec<u8>) -> Result<u32, std::io::Error> {
+        let mut dictionary = Dictionary::new(data);
+        loop {
+            match fs::read(self.dictionary_path(dictionary.id)) {
+                Ok(stored) if stored == dictionary.data => return Ok(dictionary.id),
+                Ok(_) => dictionary.id = dictionary.id.checked_add(1).unwrap_or(1),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.write_atomic(&self.dictionary_path(dictionary.id), &dictionary.data)?;
+        self.sync()?;
+
+        let id = dictionary.id;
+        self.dictionaries.lock().unwrap().insert(id, Arc::new(dictionary));
+        Ok(id)
+    }
+
+    /// Chooses the dictionary for new zstd chunks (`None` disables dictionaries)
+    pub fn set_active_dictionary(&mut self, id: Option<u32>) -> Result<(), std::io::Error> {
+        self.active_dictionary = match id {
+            Some(id) => Some(self.load_dictionary(id)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn active_dictionary(&self) -> Option<u32> {
+        self.active_dictionary.as_ref().map(|d| d.id)
+    }
+
+    /// IDs of every dictionary stored in the repository
+    pub fn list_dictionaries(&self) -> Result<Vec<u32>, std::io::Error> {
+        let dict_dir = self.root_dir.join("dicts");
+        if !dict_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dict_dir)? {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(|n| u32::from_str_radix(n, 16).ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn load_dictionary(&self, id: u32) -> Result<Arc<Dictionary>, std::io::Error> {
+        if let Some(dictionary) = self.dictionaries.lock().unwrap().get(&id) {
+            return Ok(dictionary.clone());
+        }
+        // Not NotFound: a missing dictionary means chunks are undecodable, not absent
+        let data = fs::read(self.dictionary_path(id)).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Dictionary {:08x} unreadable: {}", id, e)
+            )
+        })?;
+        let dictionary = Arc::new(Dictionary { id, data });
+        self.dictionaries.lock().unwrap().insert(id, dictionary.clone());
+        Ok(dictionary)
+    }
+
+    fn dictionary_path(&self, id: u32) -> PathBuf {
+        self.root_dir.join("dicts").join(format!("{:08x}", id))
     }
 
     /// Takes a chunk of bytes, hashes it, COMPRESSES it, and saves it to disk.
     pub fn write_chunk(&self, data: &[u8]) -> Result<String, std::io::Error> {
-        // 1. Calculate SHA-256 Hash of RAW data
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
-        let hash_string = hex::encode(result);
+        self.write_chunk_with(data, self.compression)
+    }
+
+    /// Same as `write_chunk`, but compresses with an explicit policy
+    /// (e.g. one chosen per path prefix by the FileManager).
+    pub fn write_chunk_with(
+        &self,
+        data: &[u8],
+        policy: CompressionPolicy
+    ) -> Result<String, std::io::Error> {
+        // 1. Hash the RAW data with the repository's algorithm
+        let chunk_id = self.hash_algorithm.chunk_id(&self.hash_algorithm.digest(data));
 
         // 2. Determine File Path
-        let subdir = self.root_dir.join("cas").join(&hash_string[0..2]); // Added "cas" subfolder for cleanliness
-        let file_path = subdir.join(&hash_string[2..]);
+        let file_path = self.chunk_path(&chunk_id);
 
         // 3. Deduplication Check
         if file_path.exists() {
-            // println!("Debug: Deduplicated chunk {}", &hash_string[0..8]);
-            return Ok(hash_string);
+            // println!("Debug: Deduplicated chunk {}", &chunk_id[0..8]);
+            return Ok(chunk_id);
         }
 
-        // 4. Compress the data (Level 3 is default)
-        let compressed_data = zstd::encode_all(data, 3)?;
+        // 4. Compress the data (raw if compression doesn't pay off)
+        let compressed_data = compression::encode_chunk(
+            data,
+            policy,
+            self.min_savings_percent,
+            self.active_dictionary.as_deref()
+        )?;
+
+        // 5. Write to Disk. The chunk only appears under its final name once
+        // it is complete, so the exists() check above never sees a torn chunk.
+        self.write_atomic(&file_path, &compressed_data)?;
 
-        // 5. Write to Disk
-        fs::create_dir_all(&subdir)?;
-        let mut file = File::create(&file_path)?;
-        file.write_all(&compressed_data)?;
+        // println!("Debug: Wrote new chunk {}", &chunk_id[0..8]);
+        Ok(chunk_id)
+    }
+
+    /// Makes every chunk written so far durable (their links included).
+    /// Callers must sync before persisting anything that references the chunks.
+    pub fn sync(&self) -> Result<(), std::io::Error> {
+        let dirs: Vec<PathBuf> = self.dirty_dirs.lock().unwrap().drain().collect();
+        for (i, dir) in dirs.iter().enumerate() {
+            if let Err(e) = File::open(dir).and_then(|d| d.sync_all()) {
+                // Keep the rest pending so a retry still covers them
+                self.dirty_dirs.lock().unwrap().extend(dirs[i..].iter().cloned());
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 
-        // println!("Debug: Wrote new chunk {}", &hash_string[0..8]);
-        Ok(hash_string)
+    /// Deletes temp files left behind by interrupted writes.
+    /// Only files older than `older_than` are touched, so in-flight writes
+    /// from another process sharing the store are left alone.
+    pub fn remove_stale_temp_files(&self, older_than: Duration) -> Result<usize, std::io::Error> {
+        let tmp_dir = self.root_dir.join("tmp");
+        if !tmp_dir.exists() {
+            return Ok(0);
+        }
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(tmp_dir)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() >= older_than {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Write to "tmp/", fsync, then link into place (atomic on POSIX). A crash
+    // leaves either nothing or a complete file at `path`. An existing `path`
+    // is kept: every caller names files after their content.
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+        #[cfg(test)]
+        let _serial = self.crash_gate()?;
+        let tmp_dir = self.root_dir.join("tmp");
+        fs::create_dir_all(&tmp_dir)?;
+        let tmp_path = tmp_dir.join(
+            format!("{}-{}", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed))
+        );
+
+        let result = (|| {
+            let mut file = File::create(&tmp_path)?;
+            #[cfg(test)]
+            if let Err(crash) = self.crash_check(CrashPoint::ChunkHalfWritten) {
+                file.write_all(&bytes[..bytes.len() / 2])?;
+                return Err(crash);
+            }
+            file.write_all(bytes)?;
+            file.sync_all()?;
+            #[cfg(test)]
+            self.crash_check(CrashPoint::ChunkBeforeRename)?;
+
+            let parent = path.parent().unwrap();
+            fs::create_dir_all(parent)?;
+            // Two writers racing on the same new chunk both get here. Linking
+            // (unlike rename) refuses to replace, so exactly one of them
+            // creates `path` and adds to the stored total.
+            match fs::hard_link(&tmp_path, path) {
+                Ok(()) => self.adjust_stored_bytes(bytes.len() as i64),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            fs::remove_file(&tmp_path)?;
+
+            // The new link lives in `parent`, and a new `parent` lives in its own parent
+            let mut dirty = self.dirty_dirs.lock().unwrap();
+            dirty.insert(parent.to_path_buf());
+            dirty.insert(parent.parent().unwrap().to_path_buf());
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            // A simulated crash must leave its debris behind, like a real one
+            #[cfg(test)]
+            if e.to_string() == INJECTED_CRASH {
+                return Err(e);
+            }
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        Ok(())
     }
 
     /// Reads a chunk, DECOMPRESSES it, and returns raw bytes
     pub fn read_chunk(&self, hash: &str) -> Result<Vec<u8>, std::io::Error> {
-        let subdir = self.root_dir.join("cas").join(&hash[0..2]);
-        let file_path = subdir.join(&hash[2..]);
+        Ok(self.read_chunk_shared(hash)?.as_ref().clone())
+    }
+
+    /// Like `read_chunk`, but hands out the cached buffer without copying it
+    pub fn read_chunk_shared(&self, hash: &str) -> Result<Arc<Vec<u8>>, std::io::Error> {
+        if let Some(data) = self.cache.get(hash) {
+            return Ok(data);
+        }
+        let data = Arc::new(self.read_chunk_from_disk(hash)?);
+        self.cache.insert(hash, data.clone());
+        Ok(data)
+    }
+
+    /// `read_chunk_shared` that also re-hashes the content, for callers that
+    /// hand every byte back to the user (export)
+    pub fn read_chunk_verified(&self, hash: &str) -> Result<Arc<Vec<u8>>, std::io::Error> {
+        let data = self.read_chunk_shared(hash)?;
+        let (algorithm, digest) = HashAlgorithm::from_chunk_id(hash);
+        if algorithm.digest(&data) != digest {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Content does not match its hash"));
+        }
+        Ok(data)
+    }
+
+    /// Sets the byte budget of the decompressed-chunk cache (0 disables it)
+    pub fn set_cache_capacity(&self, bytes: usize) {
+        self.cache.set_capacity(bytes);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    fn read_chunk_from_disk(&self, hash: &str) -> Result<Vec<u8>, std::io::Error> {
+        let file_path = self.chunk_path(hash);
 
         if !file_path.exists() {
             return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Chunk not found"));
@@ -60,11 +452,24 @@ impl Storage {
         let mut compressed_data = Vec::new();
         file.read_to_end(&mut compressed_data)?;
 
-        // Decompress
-        let raw_data = zstd::decode_all(&compressed_data[..])?;
+        // Decompress (the chunk header says which codec and dictionary were used)
+        let dictionary = match compression::dictionary_id(&compressed_data)? {
+            Some(id) => Some(self.load_dictionary(id)?),
+            None => None,
+        };
+        let raw_data = compression::decode_chunk(&compressed_data, dictionary.as_deref())?;
         Ok(raw_data)
     }
 
+    /// Re-hashes a stored chunk with the algorithm recorded in its ID.
+    /// Returns false if the content no longer matches (bit rot, torn write).
+    pub fn verify_chunk(&self, hash: &str) -> Result<bool, std::io::Error> {
+        let (algorithm, digest) = HashAlgorithm::from_chunk_id(hash);
+        // Bypass the cache: the point is to check what is on disk
+        let data = self.read_chunk_from_disk(hash)?;
+        Ok(algorithm.digest(&data) == digest)
+    }
+
     pub fn list_all_chunks(&self) -> Result<Vec<String>, std::io::Error> {
         let mut chunks = Vec::new();
         let cas_dir = self.root_dir.join("cas");
@@ -84,10 +489,17 @@ impl Storage {
                 // Iterate over files inside (the rest of the hash)
                 for file_entry in fs::read_dir(path)? {
                     let file_entry = file_entry?;
-                    let suffix = file_entry.file_name().into_string().unwrap();
-                    
-                    // Reconstruct full hash
-                    chunks.push(format!("{}{}", prefix, suffix));
+                    let file_name = file_entry.file_name().into_string().unwrap();
+
+                    // Reconstruct the full chunk ID (algorithm tag + digest)
+                    let (algorithm, suffix) = match file_name.split_once('.') {
+                        Some((suffix, ext)) => match ext.parse::<HashAlgorithm>() {
+                            Ok(algorithm) => (algorithm, suffix),
+                            Err(_) => continue, // Not a chunk file
+                        },
+                        None => (HashAlgorithm::Sha256, file_name.as_str()),
+                    };
+                    chunks.push(algorithm.chunk_id(&format!("{}{}", prefix, suffix)));
                 }
             }
         }
@@ -95,15 +507,110 @@ impl Storage {
     }
 
     pub fn delete_chunk(&self, hash: &str) -> Result<(), std::io::Error> {
-        let subdir = self.root_dir.join("cas").join(&hash[0..2]);
-        let file_path = subdir.join(&hash[2..]);
-        if file_path.exists() {
-            fs::remove_file(file_path)?;
+        self.cache.invalidate(hash);
+        let file_path = self.chunk_path(hash);
+        if let Ok(metadata) = fs::metadata(&file_path) {
+            fs::remove_file(&file_path)?;
+            self.adjust_stored_bytes(-(metadata.len() as i64));
         }
         // Optional: Remove subdir if empty
-        let _ = fs::remove_dir(subdir); 
+        let _ = fs::remove_dir(file_path.parent().unwrap());
         Ok(())
     }
+
+    /// On-disk (compressed) size of one chunk
+    pub fn chunk_stored_size(&self, hash: &str) -> Result<u64, std::io::Error> {
+        Ok(fs::metadata(self.chunk_path(hash))?.len())
+    }
+
+    /// Total on-disk size of all chunks (compressed, each stored once).
+    /// The first call walks the store; later calls are free.
+    pub fn stored_bytes(&self) -> Result<u64, std::io::Error> {
+        let mut stored = self.stored_bytes.lock().unwrap();
+        if let Some(bytes) = *stored {
+            return Ok(bytes);
+        }
+        let mut total = 0;
+        for hash in self.list_all_chunks()? {
+            // A chunk deleted since the listing just doesn't count
+            total += fs::metadata(self.chunk_path(&hash)).map_or(0, |m| m.len());
+        }
+        *stored = Some(total);
+        Ok(total)
+    }
+
+    fn adjust_stored_bytes(&self, delta: i64) {
+        // Nothing to adjust until someone asks and the store has been counted
+        if let Some(bytes) = self.stored_bytes.lock().unwrap().as_mut() {
+            *bytes = bytes.saturating_add_signed(delta);
+        }
+    }
+
+    /// Capacity of the filesystem holding the repository
+    pub fn disk_space(&self) -> Result<DiskSpace, std::io::Error> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString
+            ::new(self.root_dir.as_os_str().as_bytes())
+            .map_err(std::io::Error::other)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is NUL-terminated and `stat` is a valid out-pointer
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let fragment = stat.f_frsize as u64;
+        Ok(DiskSpace {
+            total_bytes: (stat.f_blocks as u64) * fragment,
+            available_bytes: (stat.f_bavail as u64) * fragment,
+            free_inodes: stat.f_favail as u64,
+        })
+    }
+
+    /// Arms a simulated crash: the `skip + 1`-th time `point` is reached, the
+    /// operation stops there and fails, leaving partial state on disk.
+    #[cfg(test)]
+    pub fn arm_crash(&self, point: CrashPoint, skip: usize) {
+        *self.crash.lock().unwrap() = Some((point, skip));
+    }
+
+    #[cfg(test)]
+    pub fn crash_check(&self, point: CrashPoint) -> Result<(), std::io::Error> {
+        let mut crash = self.crash.lock().unwrap();
+        match crash.as_mut() {
+            Some((armed, skip)) if *armed == point => {
+                if *skip > 0 {
+                    *skip -= 1;
+                    return Ok(());
+                }
+                self.crashed.store(true, Ordering::SeqCst);
+                *crash = None;
+                Err(std::io::Error::other(INJECTED_CRASH))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Chunk writes may run on several threads. While a crash is armed they
+    /// go one at a time, so it lands on a predictable write; once it has
+    /// fired, no further write starts, as if the process had died.
+    #[cfg(test)]
+    fn crash_gate(&self) -> Result<Option<std::sync::MutexGuard<'_, ()>>, std::io::Error> {
+        let armed = self.crash.lock().unwrap().is_some();
+        let guard = armed.then(|| self.crash_serial.lock().unwrap());
+        if self.crashed.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other(INJECTED_CRASH));
+        }
+        Ok(guard)
+    }
+
+    /// Maps a chunk ID to "cas/<first 2 hex>/<rest>[.<algorithm>]"
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        let (algorithm, digest) = HashAlgorithm::from_chunk_id(hash);
+        let file_name = match algorithm.file_extension() {
+            Some(ext) => format!("{}.{}", &digest[2..], ext),
+            None => digest[2..].to_string(),
+        };
+        self.root_dir.join("cas").join(&digest[0..2]).join(file_name)
+    }
 }
 
 
@@ -112,15 +619,13 @@ impl Storage {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::Path;
+    use crate::test_util::reset;
 
     #[test]
     fn test_cas_storage() {
         // Test in a temp folder
         let test_dir = "./test_storage_db";
-        if Path::new(test_dir).exists() {
-            fs::remove_dir_all(test_dir).unwrap();
-        }
+        reset(test_dir);
         let store = Storage::new(test_dir);
         
         let data1 = b"Hello World";
@@ -146,13 +651,54 @@ mod tests {
         fs::remove_dir_all(test_dir).unwrap();
     }
 
+    #[test]
+    fn test_racing_writers_count_a_chunk_once() {
+        let test_dir = "./test_storage_race";
+        reset(test_dir);
+        let store = Storage::new(test_dir);
+        let hash = store.write_chunk(b"first writer").unwrap();
+        let stored = store.stored_bytes().unwrap();
+
+        // A second writer that passed the exists() check before the first
+        // one landed: the chunk is kept, counted once, and no temp file stays
+        let path = store.chunk_path(&hash);
+        let on_disk = fs::read(&path).unwrap();
+        store.write_atomic(&path, b"second writer").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), on_disk);
+        assert_eq!(store.stored_bytes().unwrap(), stored);
+        assert_eq!(fs::read_dir(Path::new(test_dir).join("tmp")).unwrap().count(), 0);
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_dictionary_id_collisions() {
+        let test_dir = "./test_storage_dict_ids";
+        reset(test_dir);
+        let store = Storage::new(test_dir);
+        let data = b"a dictionary's worth of samples".to_vec();
+        let id = Dictionary::new(data.clone()).id;
+
+        // Another dictionary already sits at this one's ID: it is left alone
+        fs::create_dir_all(Path::new(test_dir).join("dicts")).unwrap();
+        fs::write(store.dictionary_path(id), b"someone else").unwrap();
+        let stored = store.store_dictionary(data.clone()).unwrap();
+        assert_eq!(stored, id.checked_add(1).unwrap_or(1));
+        assert_eq!(fs::read(store.dictionary_path(id)).unwrap(), b"someone else");
+        assert_eq!(store.load_dictionary(stored).unwrap().data, data);
+
+        // Storing the same bytes again finds them instead of taking a third ID
+        assert_eq!(store.store_dictionary(data).unwrap(), stored);
+        assert_eq!(store.list_dictionaries().unwrap().len(), 2);
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+
     #[test]
     fn test_compression_cycle() {
         // 1. Setup
         let test_dir = "./test_storage_zstd";
-        if std::path::Path::new(test_dir).exists() {
-            fs::remove_dir_all(test_dir).unwrap();
-        }
+        reset(test_dir);
         let store = Storage::new(test_dir);
         
         // 2. Create data
@@ -175,4 +721,77 @@ mod tests {
         // Cleanup
         fs::remove_dir_all(test_dir).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_blake3_chunk_ids() {
+        let test_dir = "./test_storage_blake3";
+        reset(test_dir);
+        let mut store = Storage::new(test_dir);
+
+        let sha_hash = store.write_chunk(b"Mixed store data").expect("Write failed");
+        store.set_hash_algorithm(HashAlgorithm::Blake3);
+        let blake_hash = store.write_chunk(b"Mixed store data").expect("Write failed");
+
+        // Legacy IDs stay bare hex, new ones carry their algorithm
+        assert_eq!(sha_hash.len(), 64);
+        assert!(blake_hash.starts_with("blake3-"), "Got {}", blake_hash);
+        assert_eq!(HashAlgorithm::from_chunk_id(&blake_hash).0, HashAlgorithm::Blake3);
+
+        // Both are readable and verifiable regardless of the current setting
+        assert_eq!(store.read_chunk(&sha_hash).unwrap(), b"Mixed store data");
+        assert_eq!(store.read_chunk(&blake_hash).unwrap(), b"Mixed store data");
+        assert!(store.verify_chunk(&sha_hash).unwrap());
+        assert!(store.verify_chunk(&blake_hash).unwrap());
+
+        // Listing reconstructs the tagged IDs
+        let mut listed = store.list_all_chunks().unwrap();
+        listed.sort();
+        let mut expected = vec![sha_hash, blake_hash.clone()];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        store.delete_chunk(&blake_hash).unwrap();
+        assert_eq!(store.list_all_chunks().unwrap().len(), 1);
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_write_never_exposes_a_torn_chunk() {
+        let test_dir = "./test_storage_atomic";
+        reset(test_dir);
+        let store = Storage::new(test_dir);
+        let data = b"Half of me hits the disk. ".repeat(100);
+
+        store.arm_crash(CrashPoint::ChunkHalfWritten, 0);
+        assert!(store.write_chunk(&data).is_err());
+        assert!(store.list_all_chunks().unwrap().is_empty(), "Torn chunk became visible");
+
+        // After a restart the retry isn't fooled by the debris and stores a valid chunk
+        let store = Storage::new(test_dir);
+        let hash = store.write_chunk(&data).expect("Retry failed");
+        assert!(store.verify_chunk(&hash).unwrap());
+        assert_eq!(store.remove_stale_temp_files(Duration::ZERO).unwrap(), 1);
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reads_are_served_from_cache() {
+        let test_dir = "./test_storage_cache";
+        reset(test_dir);
+        let store = Storage::new(test_dir);
+        let hash = store.write_chunk(b"Common header bytes").unwrap();
+
+        assert_eq!(store.read_chunk(&hash).unwrap(), b"Common header bytes");
+        assert_eq!(store.read_chunk(&hash).unwrap(), b"Common header bytes");
+        let stats = store.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // Deleting a chunk must not leave a stale copy behind
+        store.delete_chunk(&hash).unwrap();
+        assert!(store.read_chunk(&hash).is_err());
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+// src/sync.rs
+use crate::file_manager::{ FileManager, WriteError };
+use crate::import::{ self, HostEntry, ImportOptions };
+use crate::quota::normalize_prefix;
+use crate::recipe::FileKind;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+/// How `sync_tree` compares and what it may change (`better-fs sync`)
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Destination prefix and source filters, as for an import
+    pub import: ImportOptions,
+    /// Compare content, not just size and mtime (reads every source file)
+    pub checksum: bool,
+    /// Delete entries under the prefix whose source is gone
+    pub delete: bool,
+    /// Work out the changes without making any
+    pub dry_run: bool,
+}
+
+/// One change `sync_tree` made (or would make, in a dry run)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Not in the repository yet
+    Add(String),
+    /// Content (or the kind of entry) differs; it is ingested again
+    Update(String),
+    /// Same content, new mode or mtime: only the recipe is rewritten
+    Attributes(String),
+    /// Gone from the source
+    Delete(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Add(path) => write!(f, "new      {}", path),
+            Change::Update(path) => write!(f, "changed  {}", path),
+            Change::Attributes(path) => write!(f, "attrs    {}", path),
+            Change::Delete(path) => write!(f, "deleted  {}", path),
+        }
+    }
+}
+
+/// Outcome of `sync_tree`
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub changes: Vec<Change>,
+    pub unchanged: usize,
+    /// Content bytes ingested (0 in a dry run)
+    pub bytes: u64,
+    /// "path: reason" for every entry that was left out
+    pub skipped: Vec<String>,
+}
+
+/// Brings the repository under the prefix up to date with the directory
+/// `source`, ingesting only what changed. An entry is unchanged if its size
+/// and mtime match what was stored (and, with `checksum`, its content).
+/// Directories are mirrored whatever the filters, as rsync does, and their
+/// mtimes are not compared: they move whenever a child does.
+pub fn sync_tree(manager: &FileManager, source: &Path, options: &SyncOptions) -> Result<SyncReport, String> {
+    if !source.is_dir() {
+        return Err(format!("{} is not a directory", source.display()));
+    }
+    let prefix = normalize_prefix(&options.import.prefix);
+    // The prefix itself is the source directory's entry; only its parents are made here
+    if !options.dry_run {
+        import::make_prefix(manager, prefix.rsplit_once('/').map_or("", |(parent, _)| parent))?;
+    }
+    let (entries, skipped) = import::scan(source, &options.import)?;
+    let mut report = SyncReport { skipped, ..SyncReport::default() };
+
+    // 1. Add and update, parents before children
+    let seen: HashSet<String> = entries.iter().map(|entry| entry.dest.clone()).collect();
+    for entry in &entries {
+        let (change, content) = match compare(manager, entry, options.checksum) {
+            Ok((Some(change), content)) => (change, content),
+            Ok((None, _)) => {
+                report.unchanged += 1;
+                continue;
+            }
+            Err(e) => {
+                report.skipped.push(format!("{}: {}", entry.path.display(), e));
+                continue;
+            }
+        };
+        if !options.dry_run {
+            match apply(manager, entry, &change, content) {
+                Ok(bytes) => report.bytes += bytes,
+                Err(e) => {
+                    import::skip_or_fail(e, &entry.path, &mut report.skipped)?;
+                    continue;
+                }
+            }
+        }
+        report.changes.push(change);
+    }
+
+    // 2. Delete what vanished from the source. Entries the filters leave out
+    // are kept as long as the source still has them.
+    if options.delete {
+        let mut vanished: Vec<(String, FileKind)> = manager
+            .list_subtree(&prefix)?
+            .into_iter()
+            .filter(|(path, _)| *path != prefix && !seen.contains(path))
+            .filter(|(path, _)| {
+                let relative = path[prefix.len()..].trim_start_matches('/');
+                source.join(relative).symlink_metadata().is_err()
+            })
+            .map(|(path, recipe)| (path, recipe.kind))
+            .collect();
+        // Children sort after their directory; remove them first
+        vanished.reverse();
+        for (path, kind) in vanished {
+            if !options.dry_run {
+                let removed = match kind {
+                    FileKind::Directory => manager.remove_directory(&path),
+                    _ => manager.delete_file(&path),
+                };
+                if let Err(e) = removed {
+                    import::skip_or_fail(e, Path::new(&path), &mut report.skipped)?;
+                    continue;
+                }
+            }
+            report.changes.push(Change::Delete(path));
+        }
+    }
+    Ok(report)
+}
+
+// What to do about one source entry, None if it's up to date. The source
+// content comes back too if it had to be read, so `apply` needn't read it again.
+fn compare(manager: &FileManager, entry: &HostEntry, checksum: bool) -> Result<(Option<Change>, Option<Vec<u8>>), String> {
+    let Some((size, kind, meta)) = manager.stat(&entry.dest) else {
+        return Ok((Some(Change::Add(entry.dest.clone())), None));
+    };
+    if kind != entry.kind {
+        return Ok((Some(Change::Update(entry.dest.clone())), None));
+    }
+    let same_mtime = (meta.mtime_secs, meta.mtime_nanos) == (entry.meta.mtime_secs, entry.meta.mtime_nanos);
+    let mut content = None;
+    let same_content = match kind {
+        FileKind::Directory => true,
+        _ if checksum => {
+            let data = content.insert(entry.read().map_err(|e| e.to_string())?);
+            manager.content_matches(&entry.dest, data)?
+        }
+        _ => size == entry.size && same_mtime,
+    };
+    let same_attributes = meta.mode == entry.meta.mode && (same_mtime || kind == FileKind::Directory);
+    let change = match (same_content, same_attributes) {
+        (false, _) => Some(Change::Update(entry.dest.clone())),
+        (true, false) => Some(Change::Attributes(entry.dest.clone())),
+        (true, true) => None,
+    };
+    Ok((change, content))
+}
+
+// Makes `change` to the repository, with the source content `compare` read
+// if any; returns the content bytes ingested
+fn apply(manager: &FileManager, entry: &HostEntry, change: &Change, content: Option<Vec<u8>>) -> Result<u64, WriteError> {
+    if let Change::Attributes(path) = change {
+        manager.set_meta(path, entry.meta)?;
+        return Ok(0);
+    }
+    // An entry of another kind is in the way (a file where a directory is now, ...)
+    if let Some((_, kind, _)) = manager.stat(&entry.dest)
+        && kind != entry.kind
+    {
+        remove_tree(manager, &entry.dest)?;
+    }
+    if entry.kind == FileKind::Directory {
+        manager.ensure_directory(&entry.dest, entry.meta)?;
+        return Ok(0);
+    }
+    let data = match content {
+        Some(data) => data,
+        None => entry.read().map_err(|e| e.to_string())?,
+    };
+    manager.write_file_with_meta(&entry.dest, &data, entry.kind.clone(), entry.meta)?;
+    Ok(data.len() as u64)
+}
+
+// `path` and everything below it, children first
+fn remove_tree(manager: &FileManager, path: &str) -> Result<(), WriteError> {
+    for (entry, recipe) in manager.list_subtree(path)?.into_iter().rev() {
+        match recipe.kind {
+            FileKind::Directory => manager.remove_directory(&entry)?,
+            _ => manager.delete_file(&entry)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::reset;
+    use std::fs::{ self, File };
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{ Duration, SystemTime };
+
+    fn set_mtime(path: &str, mtime: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn test_sync_changes_only_what_differs() {
+        let (src, db_path) = ("./test_sync_src", "./test_db_sync");
+        reset(src);
+        reset(db_path);
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        fs::create_dir_all(format!("{}/docs", src)).unwrap();
+        for (name, content) in [("keep.txt", "same"), ("edit.txt", "before"), ("docs/gone.txt", "bye"), ("chmod.sh", "x")] {
+            let path = format!("{}/{}", src, name);
+            fs::write(&path, content).unwrap();
+            set_mtime(&path, old);
+        }
+        let manager = FileManager::new(db_path);
+        let mut options = SyncOptions::default();
+        options.import.prefix = "mirror".to_string();
+
+        // 1. The first sync adds everything, the second finds nothing to do
+        let report = sync_tree(&manager, Path::new(src), &options).unwrap();
+        assert_eq!(report.changes.len(), 6); // 4 files, docs/ and mirror/ itself
+        assert_eq!(report.changes[0], Change::Add("mirror".to_string()));
+        let report = sync_tree(&manager, Path::new(src), &options).unwrap();
+        assert!(report.changes.is_empty());
+        assert_eq!(report.unchanged, 6);
+
+        // 2. Same size and mtime hides an edit unless content is compared
+        fs::write(format!("{}/edit.txt", src), "after!").unwrap();
+        set_mtime(&format!("{}/edit.txt", src), old);
+        fs::set_permissions(format!("{}/chmod.sh", src), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::remove_file(format!("{}/docs/gone.txt", src)).unwrap();
+        fs::write(format!("{}/new.txt", src), "hello").unwrap();
+        options.delete = true;
+        options.dry_run = true;
+        let planned = sync_tree(&manager, Path::new(src), &options).unwrap().changes;
+        assert_eq!(planned, vec![
+            Change::Attributes("mirror/chmod.sh".to_string()),
+            Change::Add("mirror/new.txt".to_string()),
+            Change::Delete("mirror/docs/gone.txt".to_string())
+        ]);
+        assert!(manager.get_file_metadata("mirror/new.txt").is_none()); // Nothing done yet
+
+        options.dry_run = false;
+        options.checksum = true;
+        let report = sync_tree(&manager, Path::new(src), &options).unwrap();
+        assert_eq!(report.changes.len(), 4);
+        assert!(report.changes.contains(&Change::Update("mirror/edit.txt".to_string())));
+        assert_eq!(report.bytes, 11); // edit.txt and new.txt only
+        assert_eq!(manager.read_file("mirror/edit.txt").unwrap(), b"after!");
+        assert_eq!(manager.stat("mirror/chmod.sh").unwrap().2.mode, 0o700);
+        assert!(manager.get_file_metadata("mirror/docs/gone.txt").is_none());
+        assert!(manager.get_file_metadata("mirror/docs").is_some());
+
+        // 3. A file replaced by a directory of the same name
+        fs::remove_file(format!("{}/keep.txt", src)).unwrap();
+        fs::create_dir(format!("{}/keep.txt", src)).unwrap();
+        fs::write(format!("{}/keep.txt/inner", src), "in").unwrap();
+        sync_tree(&manager, Path::new(src), &options).unwrap();
+        assert_eq!(manager.read_file("mirror/keep.txt/inner").unwrap(), b"in");
+
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(db_path).unwrap();
+    }
+}
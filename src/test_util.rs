@@ -0,0 +1,28 @@
+// src/test_util.rs
+// Fixtures shared by the unit tests of several modules
+
+/// `len` pseudo-random bytes from an xorshift64 generator: the same `seed`
+/// always gives the same bytes, zstd can't shrink them, and different seeds
+/// share no runs long enough to dedupe.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    // splitmix64 of the seed, so neighbouring seeds start far apart (and never at 0)
+    let mut x = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x = (x ^ (x >> 31)) | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            (x >> 56) as u8
+        })
+        .collect()
+}
+
+/// Removes a test's database or scratch directory if a previous run left one
+pub fn reset(dir: &str) {
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+// src/worker_pool.rs
+use std::sync::mpsc::{ self, Sender };
+use std::sync::{ Arc, Mutex };
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of background threads pulling jobs off a shared queue.
+/// Dropping the pool lets queued jobs finish, then joins the threads.
+pub struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                std::thread::Builder
+                    ::new()
+                    .name(format!("{}-{}", name, i))
+                    .spawn(move || {
+                        loop {
+                            // Hold the lock only while taking a job, not while running it
+                            let job = receiver.lock().unwrap().recv();
+                            match job {
+                                Ok(job) => job(),
+                                Err(_) => break, // Pool dropped
+                            }
+                        }
+                    })
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool { sender: Some(sender), workers }
+    }
+
+    /// One thread per CPU
+    pub fn with_default_size(name: &str) -> Self {
+        let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+        Self::new(name, threads)
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if let Some(sender) = &self.sender {
+            // Only fails once every worker has exited, i.e. during shutdown
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    #[test]
+    fn test_runs_every_job_before_drop_returns() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = WorkerPool::new("test", 3);
+            for _ in 0..100 {
+                let counter = counter.clone();
+                pool.execute(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = WorkerPool::new("test", 2);
+        let (tx, rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        // The first job blocks until the second one has sent its message, so
+        // "second" always comes first, and only if both run at once
+        let first_done = tx.clone();
+        pool.execute(move || {
+            release_rx.recv().unwrap();
+            first_done.send("first").unwrap();
+        });
+        pool.execute(move || {
+            tx.send("second").unwrap();
+            release_tx.send(()).unwrap();
+        });
+
+        assert_eq!(rx.recv().unwrap(), "second");
+        assert_eq!(rx.recv().unwrap(), "first");
+    }
+}
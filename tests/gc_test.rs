@@ -1,10 +1,26 @@
 // tests/gc_test.rs
+// The shared src modules expose more API than each test file exercises.
+#![allow(dead_code)]
 
 // --- MODULE HACKS (To access your src code from a test file) ---
 #[path = "../src/chunker.rs"]
 mod chunker;
+#[path = "../src/prechunker.rs"]
+mod prechunker;
+#[path = "../src/chunk_cache.rs"]
+mod chunk_cache;
+#[path = "../src/compression.rs"]
+mod compression;
+#[path = "../src/quota.rs"]
+mod quota;
+#[path = "../src/recipe.rs"]
+mod recipe;
+#[path = "../src/stats.rs"]
+mod stats;
 #[path = "../src/storage.rs"]
 mod storage;
+#[path = "../src/test_util.rs"]
+mod test_util;
 #[path = "../src/file_manager.rs"]
 mod file_manager;
 // --------------------------------------------------------------
@@ -107,4 +123,36 @@ fn test_gc_does_nothing_on_clean_state() {
     assert_eq!(count_chunks_on_disk(test_dir), 1, "Data should remain");
 
     fs::remove_dir_all(test_dir).unwrap();
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_gc_and_fsck_on_mixed_hash_store() {
+    let test_dir = "./test_gc_mixed";
+    let mut manager = setup_test_env(test_dir);
+
+    // 1. An "old" file hashed with the default SHA-256...
+    manager.write_file("old.txt", b"Written before the switch").unwrap();
+    manager.write_file("doomed.txt", b"Also SHA-256, soon an orphan").unwrap();
+
+    // 2. ...then the repository moves to BLAKE3
+    manager.set_hash_algorithm(storage::HashAlgorithm::Blake3).unwrap();
+    manager.write_file("new.txt", b"Written after the switch").unwrap();
+    manager.write_file("doomed_too.txt", b"BLAKE3 orphan").unwrap();
+    assert_eq!(count_chunks_on_disk(test_dir), 4);
+
+    // 3. Orphans of both algorithms are collected, live chunks of both are kept
+    manager.delete_file("doomed.txt").unwrap();
+    manager.delete_file("doomed_too.txt").unwrap();
+    assert_eq!(manager.run_gc().unwrap(), 2);
+    assert_eq!(count_chunks_on_disk(test_dir), 2);
+
+    assert_eq!(manager.read_file("old.txt").unwrap(), b"Written before the switch");
+    assert_eq!(manager.read_file("new.txt").unwrap(), b"Written after the switch");
+
+    // 4. Fsck re-hashes each chunk with its own algorithm
+    let report = manager.run_fsck().unwrap();
+    assert!(report.is_clean(), "Unexpected fsck problems: {:?}", report);
+    assert_eq!(report.chunks_checked, 2);
+
+    fs::remove_dir_all(test_dir).unwrap();
+}
@@ -0,0 +1,156 @@
+// tests/posix_conformance.rs
+// pjdfstest-style checks of POSIX error semantics, run against a live mount:
+//
+//   better-fs mount /tmp/betterfs &
+//   BETTERFS_TEST_MOUNT=/tmp/betterfs cargo test --test posix_conformance
+//
+// Without BETTERFS_TEST_MOUNT every case is skipped (mounting needs FUSE).
+
+use std::ffi::CString;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+
+/// A fresh, empty directory on the mount for one case
+fn scratch(case: &str) -> Option<PathBuf> {
+    let Ok(mount) = std::env::var("BETTERFS_TEST_MOUNT") else {
+        eprintln!("Skipping {}: BETTERFS_TEST_MOUNT is not set", case);
+        return None;
+    };
+    let dir = Path::new(&mount).join(format!("posix_{}_{}", case, std::process::id()));
+    fs::create_dir(&dir).expect("mkdir on the mount failed");
+    Some(dir)
+}
+
+/// Best effort: a failing case can leave entries the next run won't collide with
+fn cleanup(dir: &Path) {
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn errno<T>(result: std::io::Result<T>) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(e) => e.raw_os_error().unwrap_or(-1),
+    }
+}
+
+fn renameat2(from: &Path, to: &Path, flags: u32) -> i32 {
+    let from = CString::new(from.as_os_str().as_bytes()).unwrap();
+    let to = CString::new(to.as_os_str().as_bytes()).unwrap();
+    let result = unsafe {
+        libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), flags)
+    };
+    if result == 0 { 0 } else { std::io::Error::last_os_error().raw_os_error().unwrap_or(-1) }
+}
+
+#[test]
+fn mkdir_existing_name_is_eexist() {
+    let Some(dir) = scratch("mkdir") else { return };
+    fs::create_dir(dir.join("d")).unwrap();
+    fs::write(dir.join("f"), b"x").unwrap();
+
+    assert_eq!(errno(fs::create_dir(dir.join("d"))), libc::EEXIST);
+    assert_eq!(errno(fs::create_dir(dir.join("f"))), libc::EEXIST);
+    assert_eq!(fs::read(dir.join("f")).unwrap(), b"x", "mkdir must not overwrite a file");
+    cleanup(&dir);
+}
+
+#[test]
+fn rmdir_requires_an_empty_directory() {
+    let Some(dir) = scratch("rmdir") else { return };
+    fs::create_dir(dir.join("d")).unwrap();
+    fs::write(dir.join("d/child"), b"x").unwrap();
+    fs::write(dir.join("f"), b"x").unwrap();
+
+    assert_eq!(errno(fs::remove_dir(dir.join("d"))), libc::ENOTEMPTY);
+    assert_eq!(fs::read(dir.join("d/child")).unwrap(), b"x", "children must survive");
+    assert_eq!(errno(fs::remove_dir(dir.join("f"))), libc::ENOTDIR);
+    assert_eq!(errno(fs::remove_dir(dir.join("missing"))), libc::ENOENT);
+
+    fs::remove_file(dir.join("d/child")).unwrap();
+    assert_eq!(errno(fs::remove_dir(dir.join("d"))), 0);
+    cleanup(&dir);
+}
+
+#[test]
+fn unlink_refuses_directories() {
+    let Some(dir) = scratch("unlink") else { return };
+    fs::create_dir(dir.join("d")).unwrap();
+
+    assert_eq!(errno(fs::remove_file(dir.join("d"))), libc::EISDIR);
+    assert!(dir.join("d").is_dir());
+    assert_eq!(fs::remove_file(dir.join("missing")).unwrap_err().kind(), ErrorKind::NotFound);
+    cleanup(&dir);
+}
+
+#[test]
+fn rename_over_existing_entries() {
+    let Some(dir) = scratch("rename") else { return };
+    fs::write(dir.join("a"), b"a").unwrap();
+    fs::write(dir.join("b"), b"b").unwrap();
+    fs::create_dir(dir.join("d")).unwrap();
+    fs::create_dir(dir.join("full")).unwrap();
+    fs::write(dir.join("full/x"), b"x").unwrap();
+
+    // File over file replaces it
+    fs::rename(dir.join("a"), dir.join("b")).unwrap();
+    assert_eq!(fs::read(dir.join("b")).unwrap(), b"a");
+    assert!(!dir.join("a").exists());
+
+    // Kind mismatches and non-empty targets
+    assert_eq!(errno(fs::rename(dir.join("b"), dir.join("d"))), libc::EISDIR);
+    assert_eq!(errno(fs::rename(dir.join("d"), dir.join("b"))), libc::ENOTDIR);
+    assert_eq!(errno(fs::rename(dir.join("d"), dir.join("full"))), libc::ENOTEMPTY);
+    assert_eq!(errno(fs::rename(dir.join("full"), dir.join("full/inside"))), libc::EINVAL);
+
+    // A directory moves with its children, and may replace an empty one
+    fs::rename(dir.join("full"), dir.join("d")).unwrap();
+    assert_eq!(fs::read(dir.join("d/x")).unwrap(), b"x");
+    assert!(!dir.join("full").exists());
+    cleanup(&dir);
+}
+
+#[test]
+fn renameat2_flags() {
+    let Some(dir) = scratch("renameat2") else { return };
+    fs::write(dir.join("a"), b"a").unwrap();
+    fs::write(dir.join("b"), b"b").unwrap();
+
+    assert_eq!(renameat2(&dir.join("a"), &dir.join("b"), libc::RENAME_NOREPLACE), libc::EEXIST);
+    assert_eq!(fs::read(dir.join("b")).unwrap(), b"b");
+
+    assert_eq!(renameat2(&dir.join("a"), &dir.join("b"), libc::RENAME_EXCHANGE), 0);
+    assert_eq!(fs::read(dir.join("a")).unwrap(), b"b");
+    assert_eq!(fs::read(dir.join("b")).unwrap(), b"a");
+
+    assert_eq!(renameat2(&dir.join("a"), &dir.join("c"), libc::RENAME_EXCHANGE), libc::ENOENT);
+    assert_eq!(renameat2(&dir.join("a"), &dir.join("c"), libc::RENAME_NOREPLACE), 0);
+    assert_eq!(fs::read(dir.join("c")).unwrap(), b"b");
+    cleanup(&dir);
+}
+
+#[test]
+fn exclusive_create_of_existing_file_is_eexist() {
+    let Some(dir) = scratch("excl") else { return };
+    fs::write(dir.join("f"), b"x").unwrap();
+
+    let result = fs::OpenOptions::new().write(true).create_new(true).open(dir.join("f"));
+    assert_eq!(errno(result), libc::EEXIST);
+    assert_eq!(fs::read(dir.join("f")).unwrap(), b"x");
+    cleanup(&dir);
+}
+
+#[test]
+fn truncate_by_path_on_a_closed_file() {
+    let Some(dir) = scratch("truncate") else { return };
+    fs::write(dir.join("f"), b"0123456789").unwrap();
+    let path = CString::new(dir.join("f").as_os_str().as_bytes()).unwrap();
+
+    // truncate(2) by path: no open handle, so this goes straight to the stored recipe
+    assert_eq!(unsafe { libc::truncate(path.as_ptr(), 4) }, 0);
+    assert_eq!(fs::read(dir.join("f")).unwrap(), b"0123");
+    assert_eq!(unsafe { libc::truncate(path.as_ptr(), 8) }, 0);
+    assert_eq!(fs::read(dir.join("f")).unwrap(), b"0123\0\0\0\0");
+    cleanup(&dir);
+}